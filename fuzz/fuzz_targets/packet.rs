@@ -0,0 +1,57 @@
+//! Feeds one arbitrary IPv4 packet to a listening stack, then lets the timers run.
+//!
+//! The IPv4 and TCP checksums are fixed up first, otherwise nearly every input
+//! would be dropped before it gets past the checksum checks.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::time::Duration;
+use tcpRust::sim::Simulation;
+
+fuzz_target!(|data: &[u8]| {
+    let mut packet = data.to_vec();
+    fix_checksums(&mut packet);
+
+    let mut sim = Simulation::new();
+    sim.listen(8080).unwrap();
+    let _ = sim.inject(&packet);
+    let _ = sim.advance(Duration::from_secs(2));
+});
+
+/// Recomputes the checksums of `packet` as far as its headers parse.
+fn fix_checksums(packet: &mut [u8]) {
+    let ihl = match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+        Ok(ip_h) => ip_h.slice().len(),
+        Err(_) => return,
+    };
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let checksum = !ones_complement_sum(&packet[..ihl]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    let ip_h = etherparse::Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let total_len = ip_h.total_len() as usize;
+    if total_len < ihl || total_len > packet.len() {
+        return;
+    }
+    let tcp_h = match etherparse::TcpHeaderSlice::from_slice(&packet[ihl..total_len]) {
+        Ok(tcp_h) => tcp_h,
+        Err(_) => return,
+    };
+    let datai = ihl + tcp_h.slice().len();
+    let checksum = match tcp_h.calc_checksum_ipv4(&ip_h, &packet[datai..total_len]) {
+        Ok(checksum) => checksum,
+        Err(_) => return,
+    };
+    packet[ihl + 16..ihl + 18].copy_from_slice(&checksum.to_be_bytes());
+}
+
+fn ones_complement_sum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
@@ -0,0 +1,156 @@
+//! Drives one connection with a sequence of well-formed segments, timer ticks
+//! and application calls decoded from the input.
+//!
+//! Sequence and acknowledgment numbers are mostly picked relative to what the
+//! stack last sent, so the fuzzer gets past the handshake and into the
+//! synchronized states instead of having everything dropped as out of window.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::net::Shutdown;
+use std::time::Duration;
+use tcpRust::sim::Simulation;
+
+const REMOTE: [u8; 4] = [192, 168, 0, 1];
+const LOCAL: [u8; 4] = [192, 168, 0, 2];
+const PORT: u16 = 8080;
+
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let (&b, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(b)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes([
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+        ]))
+    }
+}
+
+/// What the stack told us in its last segment.
+#[derive(Default)]
+struct Peer {
+    // next sequence number the stack expects from us
+    rcv_nxt: u32,
+    // highest sequence number the stack has sent
+    snd_nxt: u32,
+}
+
+impl Peer {
+    fn update(&mut self, sim: &mut Simulation) {
+        for frame in sim.take_sent() {
+            let ip_h = match etherparse::Ipv4HeaderSlice::from_slice(&frame.bytes) {
+                Ok(h) => h,
+                Err(_) => continue,
+            };
+            let tcp_h =
+                match etherparse::TcpHeaderSlice::from_slice(&frame.bytes[ip_h.slice().len()..]) {
+                    Ok(h) => h,
+                    Err(_) => continue,
+                };
+            let len = frame.bytes.len() - ip_h.slice().len() - tcp_h.slice().len();
+            self.rcv_nxt = tcp_h.acknowledgment_number();
+            self.snd_nxt = tcp_h
+                .sequence_number()
+                .wrapping_add(len as u32)
+                .wrapping_add(tcp_h.syn() as u32 + tcp_h.fin() as u32);
+        }
+    }
+}
+
+fn segment(input: &mut Input, peer: &Peer) -> Option<Vec<u8>> {
+    let flags = input.u8()?;
+    let seq = match input.u8()? {
+        0 => input.u32()?,
+        d => peer.rcv_nxt.wrapping_add(d as u32).wrapping_sub(128),
+    };
+    let ack = match input.u8()? {
+        0 => input.u32()?,
+        d => peer.snd_nxt.wrapping_add(d as u32).wrapping_sub(128),
+    };
+    let win = input.u16()?;
+    let len = (input.u8()? as usize) * 8;
+
+    let mut b = etherparse::PacketBuilder::ipv4(REMOTE, LOCAL, 64).tcp(54321, PORT, seq, win);
+    if flags & 0x01 != 0 {
+        b = b.fin();
+    }
+    if flags & 0x02 != 0 {
+        b = b.syn();
+    }
+    if flags & 0x04 != 0 {
+        b = b.rst();
+    }
+    if flags & 0x08 != 0 {
+        b = b.psh();
+    }
+    if flags & 0x10 != 0 {
+        b = b.ack(ack);
+    }
+
+    let payload = vec![0xaa; len];
+    let mut packet = Vec::new();
+    b.write(&mut packet, &payload).ok()?;
+    Some(packet)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = Input(data);
+    let mut sim = Simulation::new();
+    sim.listen(PORT).unwrap();
+    let mut peer = Peer::default();
+    let mut quad = None;
+
+    while let Some(op) = input.u8() {
+        match op % 6 {
+            0 | 1 => match segment(&mut input, &peer) {
+                Some(packet) => {
+                    let _ = sim.inject(&packet);
+                }
+                None => break,
+            },
+            2 => {
+                let ms = input.u16().unwrap_or(0) as u64;
+                let _ = sim.advance(Duration::from_millis(ms * 10));
+            }
+            3 => {
+                if quad.is_none() {
+                    quad = sim.accept(PORT);
+                }
+                if let Some(q) = quad {
+                    let n = input.u8().unwrap_or(0) as usize * 16;
+                    let _ = sim.write(q, &vec![0x55; n]);
+                }
+            }
+            4 => {
+                if quad.is_none() {
+                    quad = sim.accept(PORT);
+                }
+                if let Some(q) = quad {
+                    let mut buf = [0u8; 512];
+                    let _ = sim.read(q, &mut buf);
+                }
+            }
+            _ => {
+                if quad.is_none() {
+                    quad = sim.accept(PORT);
+                }
+                if let Some(q) = quad {
+                    let _ = sim.close(q, Shutdown::Write);
+                }
+            }
+        }
+        peer.update(&mut sim);
+    }
+});
@@ -0,0 +1,222 @@
+//! Async versions of `TcpListener` and `TcpStream`, enabled by the `async` feature.
+//!
+//! Instead of waiting on the interface's condvars, an operation that cannot
+//! make progress stores its task's waker on the connection (or listening
+//! port), next to those of other tasks waiting on clones, and returns
+//! `Pending`; the packet loop wakes them all once the connection becomes
+//! readable or writable, or a new connection is waiting.
+
+use futures_io::{AsyncRead, AsyncWrite};
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use crate::{try_flush, try_read, try_read_vectored, try_write, try_write_vectored};
+
+/// Has the task of `cx` woken along with the others in `wakers`, once even if it polls again.
+fn register(wakers: &mut Vec<Waker>, cx: &Context<'_>) {
+    if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+        wakers.push(cx.waker().clone());
+    }
+}
+
+pub struct TcpListener {
+    inner: crate::TcpListener,
+}
+
+impl From<crate::TcpListener> for TcpListener {
+    fn from(inner: crate::TcpListener) -> Self {
+        TcpListener { inner }
+    }
+}
+
+impl TcpListener {
+    pub async fn accept(&mut self) -> io::Result<TcpStream> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    pub fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<TcpStream>> {
+        let mut cm = self.inner.h.manager.lock().unwrap();
+        if let Err(e) = cm.check_running() {
+            return Poll::Ready(Err(e.into()));
+        }
+        let pending = cm
+            .pending
+            .get_mut(&self.inner.id)
+            .expect("port closed while listener still active");
+
+        // threads already waiting in a blocking accept go first
+        if pending.accepting.is_empty() {
+            if let Some(quad) = pending.queue.pop_front() {
+                return Poll::Ready(Ok(TcpStream {
+                    inner: crate::TcpStream {
+                        quad,
+                        h: self.inner.h.clone(),
+                    },
+                }));
+            }
+        }
+
+        if pending.nonblocking {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "no pending connections",
+            )));
+        }
+
+        register(&mut pending.wakers, cx);
+        Poll::Pending
+    }
+
+    pub fn get_ref(&self) -> &crate::TcpListener {
+        &self.inner
+    }
+}
+
+pub struct TcpStream {
+    inner: crate::TcpStream,
+}
+
+impl From<crate::TcpStream> for TcpStream {
+    fn from(inner: crate::TcpStream) -> Self {
+        TcpStream { inner }
+    }
+}
+
+impl TcpStream {
+    /// The blocking stream, for options like `shutdown` or timeouts.
+    pub fn get_ref(&self) -> &crate::TcpStream {
+        &self.inner
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let s = &self.inner;
+        let mut cm = s.h.manager.lock().unwrap();
+        let c = match cm.connection(&s.quad) {
+            Ok(c) => c,
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+
+        match try_read(c, buf) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                register(&mut c.read_wakers, cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [io::IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let s = &self.inner;
+        let mut cm = s.h.manager.lock().unwrap();
+        let c = match cm.connection(&s.quad) {
+            Ok(c) => c,
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+
+        match try_read_vectored(c, bufs, false) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                register(&mut c.read_wakers, cx);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let s = &self.inner;
+        let mut cm = s.h.manager.lock().unwrap();
+        let c = match cm.connection(&s.quad) {
+            Ok(c) => c,
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+
+        match try_write(c, buf) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                register(&mut c.write_wakers, cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let s = &self.inner;
+        let mut cm = s.h.manager.lock().unwrap();
+        let c = match cm.connection(&s.quad) {
+            Ok(c) => c,
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+
+        match try_write_vectored(c, bufs) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                register(&mut c.write_wakers, cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Ready once everything written so far has been acknowledged by the peer.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let s = &self.inner;
+        let mut cm = s.h.manager.lock().unwrap();
+        let c = match cm.connection(&s.quad) {
+            Ok(c) => c,
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+
+        match try_flush(c) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                register(&mut c.write_wakers, cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Sends our FIN after the pending data and waits for all of it to be acknowledged.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let s = &self.inner;
+        let mut cm = s.h.manager.lock().unwrap();
+        let c = match cm.connection(&s.quad) {
+            Ok(c) => c,
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+
+        if !c.closed {
+            if let Err(e) = c.close() {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        match try_flush(c) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                register(&mut c.write_wakers, cx);
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Send and receive queues made of reference-counted chunks.
+//!
+//! Data handed over as `Bytes` by `TcpStream::send_bytes` is queued as is and
+//! `TcpStream::recv_bytes` returns the received chunks without copying them;
+//! `read` and `write` copy as before, into one growing buffer at the end of
+//! the queue so small writes do not cost a chunk each.
+
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::VecDeque;
+
+#[derive(Debug, Default)]
+pub(crate) struct ChunkQueue {
+    chunks: VecDeque<Bytes>,
+    // copied data, queued after all of chunks
+    tail: BytesMut,
+    // total bytes in chunks and tail
+    len: usize,
+}
+
+impl ChunkQueue {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.tail.clear();
+        self.len = 0;
+    }
+
+    /// Appends `chunk` without copying it.
+    pub fn push(&mut self, chunk: Bytes) {
+        if chunk.is_empty() {
+            return;
+        }
+        if !self.tail.is_empty() {
+            // copied data queued earlier goes first
+            let tail = self.tail.split().freeze();
+            self.chunks.push_back(tail);
+        }
+        self.len += chunk.len();
+        self.chunks.push_back(chunk);
+    }
+
+    /// Appends a copy of `data` to the buffer at the end of the queue.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.len += data.len();
+        self.tail.extend_from_slice(data);
+    }
+
+    /// Drops the first `n` bytes.
+    pub fn advance(&mut self, mut n: usize) {
+        assert!(n <= self.len, "advanced past the end of the queue");
+        self.len -= n;
+        while n > 0 {
+            let front = match self.chunks.front_mut() {
+                Some(front) => front,
+                None => {
+                    self.tail.advance(n);
+                    return;
+                }
+            };
+            if front.len() <= n {
+                n -= front.len();
+                self.chunks.pop_front();
+            } else {
+                front.advance(n);
+                n = 0;
+            }
+        }
+    }
+
+    /// Takes the first chunk off the queue, or its first `max` bytes if it is longer.
+    pub fn pop_chunk(&mut self, max: usize) -> Option<Bytes> {
+        let chunk = match self.chunks.front_mut() {
+            Some(front) if front.len() <= max => self.chunks.pop_front().unwrap(),
+            Some(front) => front.split_to(max),
+            None if self.tail.is_empty() => return None,
+            None => {
+                let n = std::cmp::min(max, self.tail.len());
+                self.tail.split_to(n).freeze()
+            }
+        };
+        self.len -= chunk.len();
+        Some(chunk)
+    }
+
+    /// The queued bytes from `offset` on, chunk by chunk.
+    pub fn slices(&self, mut offset: usize) -> impl Iterator<Item = &[u8]> {
+        let chunks = self.chunks.iter().map(|chunk| &chunk[..]);
+        chunks
+            .chain(std::iter::once(&self.tail[..]))
+            .filter_map(move |chunk| {
+                if offset >= chunk.len() {
+                    offset -= chunk.len();
+                    return None;
+                }
+                let slice = &chunk[offset..];
+                offset = 0;
+                Some(slice)
+            })
+    }
+
+    /// Copies the bytes from `offset` on into `buf`, returns how many fit.
+    pub fn copy_to(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        for slice in self.slices(offset) {
+            if copied == buf.len() {
+                break;
+            }
+            let n = std::cmp::min(buf.len() - copied, slice.len());
+            buf[copied..copied + n].copy_from_slice(&slice[..n]);
+            copied += n;
+        }
+        copied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "abc" as a chunk, "de" copied, "fgh" as a chunk, "ij" copied.
+    fn queue() -> ChunkQueue {
+        let mut q = ChunkQueue::default();
+        q.push(Bytes::from_static(b"abc"));
+        q.extend_from_slice(b"d");
+        q.extend_from_slice(b"e");
+        q.push(Bytes::from_static(b"fgh"));
+        q.extend_from_slice(b"ij");
+        q
+    }
+
+    fn contents(q: &ChunkQueue) -> Vec<u8> {
+        q.slices(0).flatten().copied().collect()
+    }
+
+    #[test]
+    fn keeps_order() {
+        let q = queue();
+        assert_eq!(q.len(), 10);
+        assert_eq!(contents(&q), b"abcdefghij");
+        // copies in a row share one chunk
+        let slices: Vec<&[u8]> = q.slices(0).collect();
+        assert_eq!(slices, [&b"abc"[..], b"de", b"fgh", b"ij"]);
+    }
+
+    #[test]
+    fn advance_across_chunks() {
+        let mut q = queue();
+        q.advance(3);
+        assert_eq!(contents(&q), b"defghij");
+        q.advance(4);
+        assert_eq!(contents(&q), b"hij");
+        q.advance(2);
+        assert_eq!(contents(&q), b"j");
+        q.advance(1);
+        assert!(q.is_empty());
+        assert_eq!(q.slices(0).count(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn advance_past_the_end() {
+        queue().advance(11);
+    }
+
+    #[test]
+    fn pop_chunk_splits_long_chunks() {
+        let mut q = queue();
+        assert_eq!(q.pop_chunk(2).unwrap(), &b"ab"[..]);
+        assert_eq!(q.pop_chunk(2).unwrap(), &b"c"[..]);
+        assert_eq!(q.pop_chunk(usize::MAX).unwrap(), &b"de"[..]);
+        assert_eq!(q.pop_chunk(usize::MAX).unwrap(), &b"fgh"[..]);
+        assert_eq!(q.pop_chunk(1).unwrap(), &b"i"[..]);
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.pop_chunk(usize::MAX).unwrap(), &b"j"[..]);
+        assert!(q.pop_chunk(usize::MAX).is_none());
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn copy_to_from_offset() {
+        let q = queue();
+        let mut buf = [0u8; 4];
+        assert_eq!(q.copy_to(2, &mut buf), 4);
+        assert_eq!(&buf, b"cdef");
+        assert_eq!(q.copy_to(3, &mut buf), 4);
+        assert_eq!(&buf, b"defg");
+        assert_eq!(q.copy_to(8, &mut buf), 2);
+        assert_eq!(&buf[..2], b"ij");
+        assert_eq!(q.copy_to(10, &mut buf), 0);
+        assert_eq!(q.copy_to(0, &mut []), 0);
+    }
+
+    #[test]
+    fn clear() {
+        let mut q = queue();
+        q.clear();
+        assert!(q.is_empty());
+        assert_eq!(q.slices(0).count(), 0);
+        q.extend_from_slice(b"x");
+        assert_eq!(contents(&q), b"x");
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// Source of time for connection timers.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> time::Instant;
+}
+
+/// Wall clock, used by `Interface`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+}
+
+/// Clock that only moves when told to, used by the simulation driver.
+///
+/// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct VirtualClock {
+    start: time::Instant,
+    elapsed: Arc<Mutex<time::Duration>>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock {
+            start: time::Instant::now(),
+            elapsed: Default::default(),
+        }
+    }
+
+    pub fn advance(&self, by: time::Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /// Virtual time passed since the clock was created.
+    pub fn elapsed(&self) -> time::Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> time::Instant {
+        self.start + self.elapsed()
+    }
+}
@@ -0,0 +1,38 @@
+use std::io;
+use std::time;
+
+/// Where the stack sends the segments it builds and reads packets from.
+///
+/// The packet loop uses the tun interface by default, see
+/// `Interface::with_device` for others. The simulation driver records the
+/// segments instead so they can be inspected.
+pub trait Device {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Waits up to `timeout` for a packet and reads it into `buf`, `None` if none came.
+    fn recv(&mut self, buf: &mut [u8], timeout: time::Duration) -> io::Result<Option<usize>>;
+}
+
+impl Device for tun_tap::Iface {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: time::Duration) -> io::Result<Option<usize>> {
+        use std::os::unix::io::AsRawFd;
+        let mut pfd = [nix::poll::PollFd::new(
+            self.as_raw_fd(),
+            nix::poll::EventFlags::POLLIN,
+        )];
+        let n = nix::poll::poll(&mut pfd[..], timeout.as_millis() as i32)
+            .map_err(|e| e.as_errno().unwrap())?;
+        assert_ne!(n, -1);
+        if n == 0 {
+            return Ok(None);
+        }
+        assert_eq!(n, 1);
+
+        // recv() blocks the thread until the message arrives
+        tun_tap::Iface::recv(self, buf).map(Some)
+    }
+}
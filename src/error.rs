@@ -0,0 +1,70 @@
+//! Why a connection or the interface failed.
+//!
+//! The stack reports errors as `io::Error`s like `std::net` does; those made
+//! from an `Error` carry it, so callers that care can get it back with
+//! `io::Error::get_ref` and `downcast_ref::<Error>()`.
+
+use std::fmt;
+use std::io;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The peer reset the connection.
+    ConnectionReset,
+    /// The peer answered our SYN with a RST, nothing listens on that port.
+    ConnectionRefused,
+    /// The connection was reset on this side, e.g. by a zero linger.
+    ConnectionAborted,
+    /// The peer stopped answering.
+    TimedOut,
+    /// The peer broke the protocol, the connection was reset.
+    ProtocolViolation(&'static str),
+    /// Sending or receiving on the tun device failed, the packet loop stopped.
+    DeviceError(io::ErrorKind, String),
+    /// The interface was shut down.
+    InterfaceDown,
+}
+
+impl Error {
+    /// The `io::ErrorKind` the error is reported with.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::ConnectionReset => io::ErrorKind::ConnectionReset,
+            Error::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            Error::ConnectionAborted | Error::InterfaceDown => io::ErrorKind::ConnectionAborted,
+            Error::TimedOut => io::ErrorKind::TimedOut,
+            Error::ProtocolViolation(_) => io::ErrorKind::InvalidData,
+            Error::DeviceError(kind, _) => *kind,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ConnectionReset => f.write_str("connection reset by peer"),
+            Error::ConnectionRefused => f.write_str("connection refused"),
+            Error::ConnectionAborted => f.write_str("connection was reset"),
+            Error::TimedOut => f.write_str("connection timed out"),
+            Error::ProtocolViolation(what) => write!(f, "protocol violation: {what}"),
+            Error::DeviceError(_, msg) => write!(f, "device error: {msg}"),
+            Error::InterfaceDown => f.write_str("interface was shut down"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+impl From<&io::Error> for Error {
+    /// A failed send or receive on the device.
+    fn from(e: &io::Error) -> Error {
+        Error::DeviceError(e.kind(), e.to_string())
+    }
+}
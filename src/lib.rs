@@ -1,88 +1,505 @@
 #![feature(duration_float)]
 #![allow(warnings, unused)]
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io;
 use std::io::prelude::*;
-use std::net::Ipv4Addr;
-use std::sync::{Arc, Condvar, Mutex};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::Waker;
 use std::thread;
+use std::time;
 
+#[cfg(feature = "async")]
+pub mod async_io;
+mod buffer;
+mod clock;
+mod device;
+mod error;
+mod poll;
+pub mod sim;
 mod tcp;
 
-const SENDQUEUE_SIZE: usize = 1024;
+pub use bytes::Bytes;
+pub use clock::{Clock, SystemClock, VirtualClock};
+pub use device::Device;
+pub use error::Error;
+pub use poll::{Event, Events, Poll, Source, Token, Trigger};
+pub use tcp::{Available, Keepalive, State, TcpInfo};
 
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+/// Default size of a socket's send and receive buffers.
+const DEFAULT_BUFFER_SIZE: usize = 1024;
+
+/// Address the stack uses as the source of connections it opens, see `Interface::set_local_addr`.
+const DEFAULT_LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+/// Local ports handed out to outgoing connections and `bind`s to port 0, see
+/// `Interface::set_ephemeral_ports`.
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// How often connection timers are checked.
+const TICK: time::Duration = time::Duration::from_millis(10);
+
+/// How long dropping an `Interface` lets connections close before resetting them.
+const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Addresses of a connection, named from the point of view of incoming segments.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Quad {
     src: (Ipv4Addr, u16),
     dst: (Ipv4Addr, u16),
 }
 
+impl Quad {
+    /// Address of the remote end.
+    pub fn peer(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.src.0, self.src.1)
+    }
+
+    /// Address on our side.
+    pub fn local(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.dst.0, self.dst.1)
+    }
+}
+
+/// State shared by the packet loop and the sockets; threads blocked on a
+/// socket wait on that socket's own condvar, see `ConnectionManager::wake`.
 #[derive(Default)]
 struct Foobar {
     manager: Mutex<ConnectionManager>,
-    pending_var: Condvar,
-    receive_var: Condvar,
-    send_var: Condvar,
+    poll_var: Condvar,
 }
 
 type InterfaceHandle = Arc<Foobar>;
-pub struct Interface {
+pub struct Interface<D: Device + Send + 'static = tun_tap::Iface> {
     ih: Option<InterfaceHandle>,
-    jh: Option<thread::JoinHandle<io::Result<()>>>,
+    jh: Option<thread::JoinHandle<io::Result<D>>>,
+    shutdown_timeout: time::Duration,
 }
 
-impl Drop for Interface {
+impl<D: Device + Send + 'static> Drop for Interface<D> {
     fn drop(&mut self) {
-        self.ih.as_mut().unwrap().manager.lock().unwrap().terminate = true;
-
-        drop(self.ih.take());
-        self.jh
-            .take()
-            .expect("interface dropped more than once")
-            .join()
-            .unwrap()
-            .unwrap();
+        if self.jh.is_some() {
+            if let Err(e) = self.stop(self.shutdown_timeout) {
+                eprintln!("packet loop failed: {}", e);
+            }
+        }
     }
 }
 
-#[derive(Default)]
+/// Counters kept by the packet loop, see `Interface::statistics`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Statistics {
+    /// IPv4 packets read from the device
+    pub packets_received: u64,
+    /// packets dropped because the IPv4 header checksum did not match
+    pub bad_ip_checksum: u64,
+    /// packets dropped because the TCP checksum did not match
+    pub bad_tcp_checksum: u64,
+}
+
 struct ConnectionManager {
-    terminate: bool,
-    connections: HashMap<Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>,
+    // set once the interface shuts down: connections still open at this point get reset,
+    // if it is not too far out to represent
+    terminate: Option<Option<time::Instant>>,
+    // the packet loop stopped on this device error
+    failed: Option<Error>,
+    // ordered, so the timers of several connections send their segments in the same order every run
+    connections: BTreeMap<Quad, tcp::Connection>,
+    // listeners by id, several may share a port
+    pending: BTreeMap<usize, Listener>,
+    next_listener: usize,
+    clock: Arc<dyn Clock>,
+    stats: Statistics,
+    local_addr: Ipv4Addr,
+    ephemeral_ports: std::ops::RangeInclusive<u16>,
+    next_port: u16,
+    polls: HashMap<usize, poll::Registrations>,
+    next_poll: usize,
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        ConnectionManager {
+            terminate: None,
+            failed: None,
+            connections: Default::default(),
+            pending: Default::default(),
+            next_listener: 0,
+            clock: Arc::new(SystemClock),
+            stats: Default::default(),
+            local_addr: DEFAULT_LOCAL_ADDR,
+            ephemeral_ports: EPHEMERAL_PORTS,
+            next_port: *EPHEMERAL_PORTS.start(),
+            polls: Default::default(),
+            next_poll: 0,
+        }
+    }
+}
 
-    loop {
-        // we want read form the nic, but we want to make sure that we'll wake up
-        // when the next timer has to be triggered! (current method is not performance sensitive)
-        use std::os::unix::io::AsRawFd;
-        let mut pfd = [nix::poll::PollFd::new(
-            nic.as_raw_fd(),
-            nix::poll::EventFlags::POLLIN,
-        )];
-        // TODO: timers wake up every milisecond, this could be implemented in a better way
-        let n = nix::poll::poll(&mut pfd[..], 10).map_err(|e| e.as_errno().unwrap())?;
-        assert_ne!(n, -1);
-        if n == 0 {
-            let mut cmg = ih.manager.lock().unwrap();
-            for connection in cmg.connections.values_mut() {
-                // TODO: dont die on errors
-                connection.on_tick(&mut nic)?;
+/// How `Interface::bind_with` shares an address with other sockets.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BindOptions {
+    /// Like `SO_REUSEADDR`: bind even though connections still use the port,
+    /// e.g. in TIME-WAIT, and next to listeners on other addresses of the same
+    /// port (including `0.0.0.0`) that set this as well.
+    pub reuse_addr: bool,
+    /// Like `SO_REUSEPORT`: share the exact address with other listeners that
+    /// set this as well, new connections are spread among them.
+    pub reuse_port: bool,
+}
+
+/// A listening socket: connections waiting to be accepted and the listener's options.
+struct Listener {
+    addr: SocketAddrV4,
+    options: BindOptions,
+    queue: VecDeque<Quad>,
+    nonblocking: bool,
+    // `TcpListener`s sharing this listener, it closes when the last one is dropped
+    handles: usize,
+    // tickets of the threads waiting in accept, served in this order
+    accepting: VecDeque<u64>,
+    next_ticket: u64,
+    // inherited by accepted connections
+    buffers: tcp::Buffers,
+    // threads waiting in accept
+    var: Arc<Condvar>,
+    // tasks waiting in an async accept
+    wakers: Vec<Waker>,
+}
+
+/// Who has to be woken up after a packet has been processed.
+enum Wakeup {
+    Connection(Quad, tcp::Available),
+    Pending(usize),
+}
+
+impl ConnectionManager {
+    /// Starts listening on `addr`, port 0 picks an ephemeral port. Returns the listener's id
+    /// and the address it ended up on.
+    fn listen(
+        &mut self,
+        addr: SocketAddrV4,
+        options: BindOptions,
+    ) -> io::Result<(usize, SocketAddrV4)> {
+        if !addr.ip().is_unspecified() && *addr.ip() != self.local_addr {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} is not an address of the interface", addr.ip()),
+            ));
+        }
+
+        let addr = match addr.port() {
+            0 => SocketAddrV4::new(*addr.ip(), self.ephemeral_port()?),
+            _ => addr,
+        };
+        self.check_bind(addr, options)?;
+
+        let id = self.next_listener;
+        self.next_listener += 1;
+        self.pending.insert(
+            id,
+            Listener {
+                addr,
+                options,
+                queue: VecDeque::new(),
+                nonblocking: false,
+                handles: 1,
+                accepting: VecDeque::new(),
+                next_ticket: 0,
+                buffers: tcp::Buffers::default(),
+                var: Arc::default(),
+                wakers: Vec::new(),
+            },
+        );
+        println!("listening on: {addr}");
+        Ok((id, addr))
+    }
+
+    /// Whether a new listener on `addr` gets along with the sockets already there.
+    fn check_bind(&self, addr: SocketAddrV4, options: BindOptions) -> io::Result<()> {
+        let in_use = || io::Error::new(io::ErrorKind::AddrInUse, format!("{addr} already in use"));
+        // the wildcard address overlaps with every other one
+        let overlaps =
+            |ip: Ipv4Addr| ip == *addr.ip() || ip.is_unspecified() || addr.ip().is_unspecified();
+
+        for l in self.pending.values() {
+            if l.addr.port() != addr.port() || !overlaps(*l.addr.ip()) {
+                continue;
+            }
+            let shared = if l.addr.ip() == addr.ip() {
+                l.options.reuse_port && options.reuse_port
+            } else {
+                l.options.reuse_addr && options.reuse_addr
+            };
+            if !shared {
+                return Err(in_use());
+            }
+        }
+
+        let connected = self
+            .connections
+            .keys()
+            .any(|q| q.dst.1 == addr.port() && overlaps(q.dst.0));
+        if connected && !options.reuse_addr {
+            return Err(in_use());
+        }
+        Ok(())
+    }
+
+    /// Next local port in the ephemeral range that no listener or connection uses.
+    fn ephemeral_port(&mut self) -> io::Result<u16> {
+        let ports = self.ephemeral_ports.clone();
+        if !ports.contains(&self.next_port) {
+            self.next_port = *ports.start();
+        }
+        for _ in ports.clone() {
+            let port = self.next_port;
+            self.next_port = if port == *ports.end() {
+                *ports.start()
+            } else {
+                port + 1
+            };
+
+            let in_use = self.pending.values().any(|l| l.addr.port() == port)
+                || self.connections.keys().any(|q| q.dst.1 == port);
+            if !in_use {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no free ephemeral port",
+        ))
+    }
+
+    /// Closes listener `id`, resetting the connections nobody accepted yet.
+    fn unlisten(&mut self, id: usize) {
+        let mut pending = self
+            .pending
+            .remove(&id)
+            .expect("port closed while listener still active");
+        self.reset_pending(&mut pending);
+        self.forget_listener(id);
+    }
+
+    fn reset_pending(&mut self, pending: &mut Listener) {
+        for quad in pending.queue.drain(..) {
+            if let Some(c) = self.connections.get_mut(&quad) {
+                // the RST goes out on the next tick, then the connection is removed
+                c.orphaned = true;
+                c.reset();
+            }
+        }
+    }
+
+    fn check_running(&self) -> Result<(), Error> {
+        if let Some(e) = &self.failed {
+            return Err(e.clone());
+        }
+        match self.terminate {
+            Some(_) => Err(Error::InterfaceDown),
+            None => Ok(()),
+        }
+    }
+
+    /// The connection of a stream, gone once the stack is done with it.
+    fn connection(&mut self, quad: &Quad) -> Result<&mut tcp::Connection, Error> {
+        self.connections
+            .get_mut(quad)
+            .ok_or(Error::ConnectionAborted)
+    }
+
+    /// Gives up a handle on `quad`, like dropping a `TcpStream`. Dropping the
+    /// last one closes the connection in the background, or resets it if the
+    /// linger is zero.
+    ///
+    /// Returns the linger the caller should wait for the close for, if any.
+    fn release(&mut self, quad: &Quad) -> Option<time::Duration> {
+        let c = self.connections.get_mut(quad)?;
+        c.handles -= 1;
+        if c.handles > 0 {
+            // a clone still uses the connection
+            return None;
+        }
+        c.orphaned = true;
+
+        let linger = match c.linger {
+            Some(linger) if linger.is_zero() => {
+                c.reset();
+                return None;
+            }
+            linger => linger,
+        };
+
+        if !c.closed && c.check_error().is_ok() {
+            // fails if the connection is already gone, nothing left to close then
+            let _ = c.close();
+        }
+        linger
+    }
+
+    /// Shuts down the read or write side of `quad`, or both, like `TcpStream::shutdown`.
+    ///
+    /// Returns whether a `Poll` has to be woken, see `wake`.
+    fn shutdown(&mut self, quad: &Quad, how: std::net::Shutdown) -> io::Result<bool> {
+        use std::net::Shutdown;
+
+        let c = self
+            .connections
+            .get_mut(quad)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not connected"))?;
+        let before = c.waiters();
+        match how {
+            Shutdown::Read => c.shutdown_read()?,
+            Shutdown::Write => c.close()?,
+            Shutdown::Both => {
+                // neither side is shut down if the other one cannot be
+                c.check_close()?;
+                c.shutdown_read()?;
+                c.close()?;
+            }
+        }
+
+        // waiters on other handles see the end of the stream, or that writes fail
+        let woken = before.woken(&c.waiters());
+        Ok(!woken.is_empty() && self.wake(&Wakeup::Connection(*quad, woken)))
+    }
+
+    /// Stops accepting and closes every connection; the ones not closed by
+    /// `deadline` get reset.
+    fn start_shutdown(&mut self, deadline: Option<time::Instant>) {
+        self.terminate = Some(deadline);
+
+        for c in self.connections.values_mut() {
+            if !c.closed && c.check_error().is_ok() {
+                // fails for connections still connecting, those get reset at the deadline
+                let _ = c.close();
+            }
+        }
+
+        let mut pending = std::mem::take(&mut self.pending);
+        for listener in pending.values_mut() {
+            self.reset_pending(listener);
+        }
+        self.pending = pending;
+        // accept fails from now on
+        let ids: Vec<usize> = self.pending.keys().copied().collect();
+        for id in ids {
+            self.wake(&Wakeup::Pending(id));
+        }
+    }
+
+    /// Called every loop iteration while shutting down, true once the packet loop can exit.
+    fn shutdown_done(&mut self) -> bool {
+        let deadline = match self.terminate {
+            Some(deadline) => deadline,
+            None => return false,
+        };
+
+        if self.connections.values().all(|c| c.is_fin_acked()) {
+            self.abort_all(Error::InterfaceDown);
+            return true;
+        }
+
+        if deadline.is_some_and(|d| self.clock.now() >= d) {
+            for c in self.connections.values_mut() {
+                if !c.is_fin_acked() {
+                    c.reset();
+                }
+            }
+        }
+        false
+    }
+
+    /// Fails every connection that could still be waited on, nothing drives them anymore.
+    ///
+    /// Every socket is woken and reported to pollers, the caller wakes the `Poll`s.
+    fn abort_all(&mut self, err: Error) {
+        let quads: Vec<Quad> = self.connections.keys().copied().collect();
+        for q in quads {
+            let c = self.connections.get_mut(&q).unwrap();
+            if !(c.is_rcv_closed() && c.is_snd_closed()) {
+                c.abort(err.clone());
             }
+            self.wake(&Wakeup::Connection(q, tcp::Available::all()));
+        }
+        let ids: Vec<usize> = self.pending.keys().copied().collect();
+        for id in ids {
+            self.wake(&Wakeup::Pending(id));
+        }
+    }
 
-            continue;
+    /// The packet loop stopped on `e`: connections fail with it, and so does
+    /// anything else waiting on the interface.
+    fn fail(&mut self, e: &io::Error) {
+        let err = Error::from(e);
+        // before aborting, so accept fails once it is woken
+        self.failed = Some(err.clone());
+        self.abort_all(err);
+    }
+
+    /// Wakes the threads and tasks waiting on what `wakeup` made available and
+    /// records it for edge-triggered pollers.
+    ///
+    /// Returns whether a `Poll` has the socket registered and has to be woken too.
+    fn wake(&mut self, wakeup: &Wakeup) -> bool {
+        match *wakeup {
+            Wakeup::Connection(q, a) => {
+                if let Some(c) = self.connections.get_mut(&q) {
+                    if a.contains(tcp::Available::READ) {
+                        c.read_var.notify_all();
+                        c.read_wakers.drain(..).for_each(Waker::wake);
+                    }
+                    if a.contains(tcp::Available::WRITE) {
+                        c.write_var.notify_all();
+                        c.write_wakers.drain(..).for_each(Waker::wake);
+                    }
+                }
+            }
+            Wakeup::Pending(id) => {
+                if let Some(l) = self.pending.get_mut(&id) {
+                    l.var.notify_all();
+                    l.wakers.drain(..).for_each(Waker::wake);
+                }
+            }
         }
-        assert_eq!(n, 1);
+        self.mark_ready(wakeup)
+    }
 
-        // recv() blocks the thread until the message arrives
-        let nbytes = nic.recv(&mut buf[..])?;
+    fn on_tick(&mut self, nic: &mut dyn Device) -> Vec<Wakeup> {
+        let mut wakeups = Vec::new();
+        for (q, connection) in self.connections.iter_mut() {
+            let before = connection.waiters();
+            match connection.on_tick(nic) {
+                Ok(()) => {
+                    // e.g. a keepalive timeout failing the connection
+                    let woken = before.woken(&connection.waiters());
+                    if !woken.is_empty() {
+                        wakeups.push(Wakeup::Connection(*q, woken));
+                    }
+                }
+                Err(e) => wakeups.push(abort(*q, connection, e)),
+            }
+        }
 
-        // TODO: if self.terminate && Arc::get_strong_refs(ih) == 1; then tear down all connections and return
+        // connections whose stream was dropped go away once they are closed
+        let finished: Vec<Quad> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.is_finished())
+            .map(|(q, _)| *q)
+            .collect();
+        for q in finished {
+            let c = self.connections.remove(&q).unwrap();
+            // a lingering drop may still be waiting on it
+            c.write_var.notify_all();
+            self.forget_connection(&q);
+        }
+        wakeups
+    }
 
+    fn on_packet(&mut self, nic: &mut dyn Device, packet: &[u8]) -> Option<Wakeup> {
         // we cannot use it since we are using tuntap mode: without_packet_info
         //
         // if s/without_package_info/new/:
@@ -94,60 +511,101 @@ fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
         //     continue;
         // }
 
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
+        match etherparse::Ipv4HeaderSlice::from_slice(packet) {
             Ok(ip_h) => {
+                self.stats.packets_received += 1;
                 let ip_src = ip_h.source_addr();
                 let ip_dst = ip_h.destination_addr();
                 if (ip_h.protocol() != 0x06) {
                     eprintln!("BAD PROTOCOL");
                     // not tcp
-                    continue;
+                    return None;
+                }
+
+                if !ipv4_checksum_ok(ip_h.slice()) {
+                    eprintln!("dropping packet with bad ip checksum");
+                    self.stats.bad_ip_checksum += 1;
+                    return None;
+                }
+
+                // anything past the ip total length is padding
+                let total_len = ip_h.total_len() as usize;
+                if total_len < ip_h.slice().len() || total_len > packet.len() {
+                    eprintln!("ignoring truncated package");
+                    return None;
                 }
+                let packet = &packet[..total_len];
 
-                match etherparse::TcpHeaderSlice::from_slice(&buf[ip_h.slice().len()..nbytes]) {
+                match etherparse::TcpHeaderSlice::from_slice(&packet[ip_h.slice().len()..]) {
                     Ok(tcp_h) => {
-                        use std::collections::hash_map::Entry;
+                        use std::collections::btree_map::Entry;
                         let datai = ip_h.slice().len() + tcp_h.slice().len();
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let cm = &mut *cmg;
+                        if tcp_h.calc_checksum_ipv4(&ip_h, &packet[datai..]).ok()
+                            != Some(tcp_h.checksum())
+                        {
+                            eprintln!("dropping packet with bad tcp checksum");
+                            self.stats.bad_tcp_checksum += 1;
+                            return None;
+                        }
+
                         let q = Quad {
                             src: (ip_src, tcp_h.source_port()),
                             dst: (ip_dst, tcp_h.destination_port()),
                         };
 
-                        match cm.connections.entry(q) {
+                        match self.connections.entry(q) {
                             Entry::Occupied(mut c) => {
-                                let a = c.get_mut().on_packet(
-                                    &mut nic,
-                                    ip_h,
-                                    tcp_h,
-                                    &buf[datai..nbytes],
-                                )?;
-
-                                // TODO: compare before/after
-                                drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.receive_var.notify_all()
-                                }
-                                if a.contains(tcp::Available::WRITE) {
-                                    ih.send_var.notify_all()
+                                let c = c.get_mut();
+                                let before = c.waiters();
+                                match c.on_packet(nic, ip_h, tcp_h, &packet[datai..]) {
+                                    Ok(_) => {
+                                        // only wake whoever waits for something that just happened
+                                        let woken = before.woken(&c.waiters());
+                                        if woken.is_empty() {
+                                            None
+                                        } else {
+                                            Some(Wakeup::Connection(q, woken))
+                                        }
+                                    }
+                                    Err(e) => Some(abort(q, c, e)),
                                 }
                             }
                             Entry::Vacant(e) => {
                                 eprintln!("got packet for unknown quad {:?}", q);
-                                if let Some(pending) = cm.pending.get_mut(&tcp_h.destination_port())
-                                {
-                                    eprintln!("listening port, so accepting the connection");
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut nic,
-                                        ip_h,
-                                        tcp_h,
-                                        &buf[datai..nbytes],
-                                    )? {
+                                let listener = find_listener(&self.pending, &q);
+                                let pending = listener.and_then(|id| self.pending.get_mut(&id));
+                                let pending = match pending {
+                                    Some(pending) if tcp_h.syn() && self.terminate.is_none() => {
+                                        pending
+                                    }
+                                    _ => {
+                                        // nothing listening, not a connection request, or shutting down
+                                        let data = &packet[datai..];
+                                        if let Err(e) = tcp::send_reset(nic, &ip_h, &tcp_h, data) {
+                                            eprintln!("failed to reset {:?}: {}", q, e);
+                                        }
+                                        return None;
+                                    }
+                                };
+
+                                eprintln!("listening port, so accepting the connection");
+                                match tcp::Connection::accept(
+                                    nic,
+                                    self.clock.clone(),
+                                    ip_h,
+                                    tcp_h,
+                                    &packet[datai..],
+                                    pending.buffers,
+                                ) {
+                                    Ok(Some(c)) => {
                                         e.insert(c); // insert it to connections
-                                        pending.push_back(q); // insert it to pending
-                                        drop(cmg);
-                                        ih.pending_var.notify_all();
+                                        pending.queue.push_back(q); // insert it to pending
+                                        listener.map(Wakeup::Pending)
+                                    }
+                                    Ok(None) => None,
+                                    Err(e) => {
+                                        eprintln!("failed to accept {:?}: {}", q, e);
+                                        None
                                     }
                                 }
                             }
@@ -155,221 +613,1261 @@ fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
                     }
                     Err(e) => {
                         eprintln!("ignoring weird package {:?}", e);
+                        None
                     }
                 }
             }
             Err(e) => {
                 eprintln!("ignoring weird package {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+/// The listener a connection request for `q` goes to: one bound to the exact
+/// address beats the wildcard, and the connections of the peers are spread
+/// over listeners sharing an address.
+fn find_listener(listeners: &BTreeMap<usize, Listener>, q: &Quad) -> Option<usize> {
+    let on = |ip: Ipv4Addr| -> Vec<usize> {
+        listeners
+            .iter()
+            .filter(|(_, l)| *l.addr.ip() == ip && l.addr.port() == q.dst.1)
+            .map(|(&id, _)| id)
+            .collect()
+    };
+    let mut ids = on(q.dst.0);
+    if ids.is_empty() {
+        ids = on(Ipv4Addr::UNSPECIFIED);
+    }
+    if ids.is_empty() {
+        return None;
+    }
+
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    q.src.hash(&mut hasher);
+    Some(ids[hasher.finish() as usize % ids.len()])
+}
+
+/// A failing connection only takes itself down, not the packet loop.
+fn abort(q: Quad, c: &mut tcp::Connection, e: io::Error) -> Wakeup {
+    eprintln!("aborting connection {:?}: {}", q, e);
+    c.abort(Error::from(&e));
+    Wakeup::Connection(q, tcp::Available::READ | tcp::Available::WRITE)
+}
+
+/// Waits on `var` until woken up, failing with `TimedOut` once `deadline` has passed.
+fn wait<'a>(
+    var: &Condvar,
+    cm: MutexGuard<'a, ConnectionManager>,
+    deadline: Option<time::Instant>,
+) -> io::Result<MutexGuard<'a, ConnectionManager>> {
+    match deadline {
+        None => Ok(var.wait(cm).unwrap()),
+        Some(deadline) => {
+            let now = time::Instant::now();
+            if now >= deadline {
+                return Err(Error::TimedOut.into());
             }
+            Ok(var.wait_timeout(cm, deadline - now).unwrap().0)
         }
     }
+}
+
+/// The instant `timeout` from now, `None` (no deadline) if that is too far out to represent.
+fn deadline(timeout: time::Duration) -> Option<time::Instant> {
+    time::Instant::now().checked_add(timeout)
+}
+
+fn check_keepalive(keepalive: Option<Keepalive>) -> io::Result<()> {
+    match keepalive {
+        Some(k) if k.idle.is_zero() || k.interval.is_zero() || k.probes == 0 => Err(
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid keepalive settings"),
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn check_buffer_size(size: usize) -> io::Result<()> {
+    if size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "buffer size must not be 0",
+        ));
+    }
+    Ok(())
+}
+
+/// std refuses zero timeouts rather than treating them as non-blocking.
+fn check_timeout(timeout: Option<time::Duration>) -> io::Result<()> {
+    if timeout == Some(time::Duration::ZERO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
     Ok(())
 }
 
+/// The one's complement sum over a header including its checksum field is all ones.
+fn ipv4_checksum_ok(header: &[u8]) -> bool {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum == 0xffff
+}
+
+fn packet_loop<D: Device>(mut nic: D, ih: InterfaceHandle) -> io::Result<D> {
+    let mut buf = [0u8; 1504];
+
+    loop {
+        if ih.manager.lock().unwrap().shutdown_done() {
+            // every socket was made ready, pollers find out it failed
+            ih.poll_var.notify_all();
+            return Ok(nic);
+        }
+
+        // we want read form the nic, but we want to make sure that we'll wake up
+        // when the next timer has to be triggered! (current method is not performance sensitive)
+        // TODO: timers wake up every milisecond, this could be implemented in a better way
+        let nbytes = match nic.recv(&mut buf[..], TICK)? {
+            Some(nbytes) => nbytes,
+            None => {
+                let mut cm = ih.manager.lock().unwrap();
+                let wakeups = cm.on_tick(&mut nic);
+                let polled = wakeups.iter().fold(false, |polled, w| cm.wake(w) | polled);
+                drop(cm);
+                if polled {
+                    ih.poll_var.notify_all();
+                }
+                continue;
+            }
+        };
+
+        // only device errors end the loop, a failing connection is aborted on its own
+        let mut cm = ih.manager.lock().unwrap();
+        let wakeup = cm.on_packet(&mut nic, &buf[..nbytes]);
+        let polled = wakeup.is_some_and(|w| cm.wake(&w));
+        drop(cm);
+        if polled {
+            ih.poll_var.notify_all();
+        }
+    }
+}
+
 impl Interface {
     pub fn new() -> io::Result<Self> {
         let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
+        Ok(Interface::with_device(nic, Arc::new(SystemClock)))
+    }
+}
 
-        let ih: InterfaceHandle = Arc::default();
+impl<D: Device + Send + 'static> Interface<D> {
+    /// Runs the stack on `nic` instead of the tun interface, with connection
+    /// timers going by `clock`.
+    pub fn with_device(nic: D, clock: Arc<dyn Clock>) -> Self {
+        let ih: InterfaceHandle = Arc::new(Foobar {
+            manager: Mutex::new(ConnectionManager {
+                clock,
+                ..Default::default()
+            }),
+            poll_var: Condvar::new(),
+        });
 
         let jh = {
             let ih = ih.clone();
-            thread::spawn(move || packet_loop(nic, ih))
+            thread::spawn(move || {
+                let r = packet_loop(nic, ih.clone());
+                if let Err(e) = &r {
+                    eprintln!("packet loop failed: {}", e);
+                    ih.manager.lock().unwrap().fail(e);
+                    ih.poll_var.notify_all();
+                }
+                r
+            })
         };
 
-        Ok(Interface {
+        Interface {
             ih: Some(ih),
             jh: Some(jh),
-        })
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// Closes every connection and stops the packet loop, handing back the device.
+    ///
+    /// New connections are refused right away and connections nobody accepted
+    /// yet are reset. The others get `timeout`, as the interface's clock counts
+    /// it, to finish sending and have their FIN acknowledged, after that they
+    /// are reset. Streams and listeners left over fail from then on.
+    pub fn shutdown(mut self, timeout: time::Duration) -> io::Result<D> {
+        self.stop(timeout)
+    }
+
+    /// Grace period dropping the interface gives connections to close, see `shutdown`.
+    pub fn set_shutdown_timeout(&mut self, timeout: time::Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    fn stop(&mut self, timeout: time::Duration) -> io::Result<D> {
+        let ih = self.ih.take().expect("interface shut down more than once");
+        let mut cm = ih.manager.lock().unwrap();
+        // the packet loop checks it against the connections' clock
+        let deadline = cm.clock.now().checked_add(timeout);
+        cm.start_shutdown(deadline);
+        drop(cm);
+        // listeners turned readable
+        ih.poll_var.notify_all();
+        drop(ih);
+
+        self.jh
+            .take()
+            .expect("interface shut down more than once")
+            .join()
+            .map_err(|_| io::Error::other("packet loop panicked"))?
     }
 
+    pub fn statistics(&self) -> Statistics {
+        self.ih.as_ref().unwrap().manager.lock().unwrap().stats
+    }
+
+    /// Listens on `port` of every address, port 0 picks one from the ephemeral range.
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
-        use std::collections::hash_map::Entry;
-        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        self.bind_with(
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port),
+            BindOptions::default(),
+        )
+    }
 
-        match cm.pending.entry(port) {
-            Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
-                println!("listening port: {port}")
-            }
-            Entry::Occupied(_) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::AddrInUse,
-                    format!("port: {port} already in bound"),
-                ));
-            }
-        };
+    /// Listens on `addr`, which is either `0.0.0.0` or the interface's local address.
+    pub fn bind_addr(&mut self, addr: SocketAddrV4) -> io::Result<TcpListener> {
+        self.bind_with(addr, BindOptions::default())
+    }
+
+    /// Like `bind_addr`, sharing the address with other sockets as `options` allow.
+    pub fn bind_with(
+        &mut self,
+        addr: SocketAddrV4,
+        options: BindOptions,
+    ) -> io::Result<TcpListener> {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        cm.check_running()?;
+        let (id, addr) = cm.listen(addr, options)?;
         drop(cm);
         Ok(TcpListener {
-            port,
+            id,
+            addr,
             h: self.ih.as_mut().unwrap().clone(),
         })
     }
+
+    /// Local ports handed out to `connect` and to `bind` with port 0.
+    pub fn set_ephemeral_ports(&mut self, ports: std::ops::RangeInclusive<u16>) -> io::Result<()> {
+        if ports.is_empty() || *ports.start() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid ephemeral port range",
+            ));
+        }
+        self.ih
+            .as_mut()
+            .unwrap()
+            .manager
+            .lock()
+            .unwrap()
+            .ephemeral_ports = ports;
+        Ok(())
+    }
+
+    /// Source address for connections opened with `connect`.
+    pub fn set_local_addr(&mut self, addr: Ipv4Addr) {
+        self.ih.as_mut().unwrap().manager.lock().unwrap().local_addr = addr;
+    }
+
+    pub fn connect(&mut self, addr: SocketAddrV4) -> io::Result<TcpStream> {
+        self.connect_until(addr, None)
+    }
+
+    /// Like `connect`, but gives up with `TimedOut` if the handshake takes longer than `timeout`.
+    pub fn connect_timeout(
+        &mut self,
+        addr: SocketAddrV4,
+        timeout: time::Duration,
+    ) -> io::Result<TcpStream> {
+        check_timeout(Some(timeout))?;
+        self.connect_until(addr, deadline(timeout))
+    }
+
+    fn connect_until(
+        &mut self,
+        addr: SocketAddrV4,
+        deadline: Option<time::Instant>,
+    ) -> io::Result<TcpStream> {
+        let h = self.ih.as_mut().unwrap().clone();
+        let mut cm = h.manager.lock().unwrap();
+        cm.check_running()?;
+
+        let quad = Quad {
+            src: (*addr.ip(), addr.port()),
+            dst: (cm.local_addr, cm.ephemeral_port()?),
+        };
+        let c = tcp::Connection::connect(cm.clock.clone(), quad.dst, quad.src);
+        cm.connections.insert(quad, c);
+
+        // the packet loop sends the SYN on its next tick
+        loop {
+            let c = cm.connection(&quad)?;
+            if let Err(e) = c.check_error() {
+                cm.connections.remove(&quad);
+                return Err(e);
+            }
+
+            if !c.is_connecting() {
+                drop(cm);
+                return Ok(TcpStream { quad, h });
+            }
+
+            let var = c.write_var.clone();
+            cm = match wait(&var, cm, deadline) {
+                Ok(cm) => cm,
+                Err(e) => {
+                    // nobody will ever use the half-open connection
+                    h.manager.lock().unwrap().connections.remove(&quad);
+                    return Err(e);
+                }
+            };
+        }
+    }
 }
 
 pub struct TcpListener {
-    port: u16,
+    id: usize,
+    addr: SocketAddrV4,
     h: InterfaceHandle,
 }
 
 impl Drop for TcpListener {
+    /// Once the last clone is gone, resets the connections still waiting to be
+    /// accepted and further SYNs to the port get a RST.
     fn drop(&mut self) {
         let mut cm = self.h.manager.lock().unwrap();
         let pending = cm
             .pending
-            .remove(&self.port)
+            .get_mut(&self.id)
             .expect("port closed while listener still active");
-
-        for quad in pending {
-            // TODO: terminate cm.connections[quad]
-            unimplemented!();
+        pending.handles -= 1;
+        if pending.handles == 0 {
+            cm.unlisten(self.id);
         }
     }
 }
 
-impl TcpListener {
-    pub fn accept(&mut self) -> io::Result<TcpStream> {
-        let mut cm = self.h.manager.lock().unwrap();
-        loop {
-            if let Some(quad) = cm
-                .pending
-                .get_mut(&self.port)
-                .expect("port closed while listener still active")
-                .pop_front()
-            {
-                return Ok(TcpStream {
-                    quad,
-                    h: self.h.clone(),
-                });
-            }
-
-            cm = self.h.pending_var.wait(cm).unwrap();
-        }
-    }
-
-    // TODO: fix connect function
-    pub fn connect(&mut self, quad: Quad) -> io::Result<TcpStream> {
+/// Another handle to the same listener, e.g. for a second thread accepting connections.
+impl Clone for TcpListener {
+    fn clone(&self) -> Self {
         let mut cm = self.h.manager.lock().unwrap();
-        loop {
-            if let Some(quad) = cm
-                .pending
-                .get_mut(&self.port)
-                .expect("port closed while listener still active")
-                .pop_front()
-            {
-                return Ok(TcpStream {
-                    quad,
-                    h: self.h.clone(),
-                });
-            }
+        cm.pending
+            .get_mut(&self.id)
+            .expect("port closed while listener still active")
+            .handles += 1;
+        TcpListener {
+            id: self.id,
+            addr: self.addr,
+            h: self.h.clone(),
         }
     }
 }
 
-pub struct TcpStream {
-    quad: Quad,
-    h: InterfaceHandle,
+/// Iterator over the connections accepted by a listener, see `TcpListener::incoming`.
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
 }
 
-impl Drop for TcpStream {
-    fn drop(&mut self) {
-        let cm = self.h.manager.lock().unwrap();
-        // TODO: send FIN on cm.connections[quad]
-        // TODO: _eventually_ remove self.quad from cm.connections
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<io::Result<TcpStream>> {
+        Some(self.listener.accept())
     }
 }
 
-impl Read for TcpStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut cm = self.h.manager.lock().unwrap();
+impl TcpListener {
+    /// Waits for a connection. Threads accepting on clones of one listener get
+    /// the connections in the order they started waiting.
+    pub fn accept(&self) -> io::Result<TcpStream> {
+        self.accept_until(None)
+    }
 
-        loop {
-            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-                io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated")
-            })?;
+    /// Accepts connections forever, like `accept` in a loop; never returns `None`.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
 
-            if c.is_rcv_closed() && c.incoming.is_empty() {
-                // no more data to read, and no need to block, because there wont be any more
+    /// Like `clone`, for symmetry with `std::net::TcpListener`.
+    pub fn try_clone(&self) -> io::Result<TcpListener> {
+        Ok(self.clone())
+    }
 
-                return Ok(0);
+    /// Like `accept`, but gives up with `TimedOut` if no connection arrives within `timeout`.
+    pub fn accept_timeout(&self, timeout: time::Duration) -> io::Result<TcpStream> {
+        check_timeout(Some(timeout))?;
+        self.accept_until(deadline(timeout))
+    }
+
+    fn accept_until(&self, deadline: Option<time::Instant>) -> io::Result<TcpStream> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let mut ticket = None;
+        let result = loop {
+            if let Err(e) = cm.check_running() {
+                break Err(e.into());
             }
+            let pending = cm
+                .pending
+                .get_mut(&self.id)
+                .expect("port closed while listener still active");
 
-            if !c.incoming.is_empty() {
-                // vecdeque is like ring buffer, bytes we read could be in two seperate positions
-                let mut nread = 0;
-                let (head, tail) = c.incoming.as_slices();
-                let hread = std::cmp::min(buf.len(), head.len());
-                buf[..hread].copy_from_slice(&head[..hread]);
-                nread += hread;
-                let tread = std::cmp::min(buf.len() - nread, tail.len());
-                buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
-                nread += tread;
-                drop(c.incoming.drain(..nread));
+            // a thread that is not waiting yet queues up behind those that are
+            let first = match ticket {
+                Some(t) => pending.accepting.front() == Some(&t),
+                None => pending.accepting.is_empty(),
+            };
+            if first {
+                if let Some(quad) = pending.queue.pop_front() {
+                    break Ok(TcpStream {
+                        quad,
+                        h: self.h.clone(),
+                    });
+                }
+            }
 
-                return Ok(nread);
+            if pending.nonblocking {
+                break Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no pending connections",
+                ));
+            }
+
+            if ticket.is_none() {
+                ticket = Some(pending.next_ticket);
+                pending.accepting.push_back(pending.next_ticket);
+                pending.next_ticket += 1;
             }
+            let var = pending.var.clone();
+            cm = match wait(&var, cm, deadline) {
+                Ok(cm) => cm,
+                Err(e) => {
+                    cm = self.h.manager.lock().unwrap();
+                    break Err(e);
+                }
+            };
+        };
 
-            cm = self.h.receive_var.wait(cm).unwrap();
+        if let Some(t) = ticket {
+            let pending = cm.pending.get_mut(&self.id).unwrap();
+            pending.accepting.retain(|&w| w != t);
+            if !pending.queue.is_empty() {
+                // the next thread in line takes the rest, or a task once no thread waits
+                pending.var.notify_all();
+                if pending.accepting.is_empty() {
+                    pending.wakers.drain(..).for_each(Waker::wake);
+                }
+            }
         }
+        result
+    }
+
+    /// The address the listener was bound to, with the port picked for port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        Ok(self.addr)
+    }
+
+    /// Send buffer size of connections accepted from now on, see `TcpStream::set_send_buffer_size`.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        check_buffer_size(size)?;
+        let mut cm = self.h.manager.lock().unwrap();
+        cm.pending
+            .get_mut(&self.id)
+            .expect("port closed while listener still active")
+            .buffers
+            .send = size;
+        Ok(())
+    }
+
+    /// Receive buffer size of connections accepted from now on, see `TcpStream::set_recv_buffer_size`.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        check_buffer_size(size)?;
+        let mut cm = self.h.manager.lock().unwrap();
+        cm.pending
+            .get_mut(&self.id)
+            .expect("port closed while listener still active")
+            .buffers
+            .recv = size;
+        Ok(())
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        let cm = self.h.manager.lock().unwrap();
+        Ok(cm.pending[&self.id].buffers.send)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        let cm = self.h.manager.lock().unwrap();
+        Ok(cm.pending[&self.id].buffers.recv)
+    }
+
+    /// In non-blocking mode `accept` returns `WouldBlock` instead of waiting for a connection.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        cm.pending
+            .get_mut(&self.id)
+            .expect("port closed while listener still active")
+            .nonblocking = nonblocking;
+        Ok(())
     }
 }
 
-impl Write for TcpStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+pub struct TcpStream {
+    quad: Quad,
+    h: InterfaceHandle,
+}
+
+impl Drop for TcpStream {
+    /// Closes the connection, the FIN goes out after any pending data and the
+    /// packet loop finishes the close in the background; see `set_linger`.
+    fn drop(&mut self) {
         let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "stream was terminated unexpectedly",
-            )
-        })?;
-
-        if c.unacked.len() >= SENDQUEUE_SIZE {
-            // TODO: block
-            return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ));
+        let deadline = match cm.release(&self.quad) {
+            Some(linger) => deadline(linger),
+            None => return,
+        };
+        loop {
+            let c = match cm.connections.get(&self.quad) {
+                Some(c) => c,
+                None => return,
+            };
+            if c.is_fin_acked() {
+                return;
+            }
+
+            let var = c.write_var.clone();
+            cm = match wait(&var, cm, deadline) {
+                Ok(cm) => cm,
+                // timed out, the close goes on in the background
+                Err(_) => return,
+            };
         }
+    }
+}
 
-        if c.is_snd_closed() {
-            // no more data to write // and no need to block, because there wont be any more
+/// Copies received data out of `c`, `None` if there is none yet and the caller has to wait.
+fn try_read(c: &mut tcp::Connection, buf: &mut [u8]) -> Option<io::Result<usize>> {
+    try_read_vectored(c, &mut [io::IoSliceMut::new(buf)], false)
+}
 
-            return Ok(0);
-        }
+/// Like `try_read`, filling `bufs` in order; `peek` leaves the data in `c`.
+fn try_read_vectored(
+    c: &mut tcp::Connection,
+    bufs: &mut [io::IoSliceMut<'_>],
+    peek: bool,
+) -> Option<io::Result<usize>> {
+    if let Err(e) = c.check_error() {
+        return Some(Err(e));
+    }
+
+    if c.rd_shutdown || (c.is_rcv_closed() && c.incoming.is_empty()) {
+        // no more data to read, and no need to block, because there wont be any more
+        return Some(Ok(0));
+    }
+
+    if c.incoming.is_empty() {
+        return None;
+    }
+
+    // the data may span several chunks, and so may each buffer
+    let mut nread = 0;
+    for buf in bufs.iter_mut() {
+        nread += c.incoming.copy_to(nread, buf);
+    }
+    if !peek {
+        c.incoming.advance(nread);
+    }
+
+    Some(Ok(nread))
+}
+
+/// Queues `buf` on `c`, `None` if the send queue is full and the caller has to wait.
+fn try_write(c: &mut tcp::Connection, buf: &[u8]) -> Option<io::Result<usize>> {
+    try_write_vectored(c, &[io::IoSlice::new(buf)])
+}
+
+/// Like `try_write`, queueing as much of `bufs` in order as fits.
+fn try_write_vectored(
+    c: &mut tcp::Connection,
+    bufs: &[io::IoSlice<'_>],
+) -> Option<io::Result<usize>> {
+    if let Err(e) = c.check_error() {
+        return Some(Err(e));
+    }
+
+    if c.is_snd_closed() {
+        // no more data to write // and no need to block, because there wont be any more
+        return Some(Ok(0));
+    }
+
+    if c.unacked.len() >= c.buffers.send {
+        return None;
+    }
+
+    let space = c.buffers.send - c.unacked.len();
+    let mut nwrite = 0;
+    for buf in bufs {
+        let n = std::cmp::min(buf.len(), space - nwrite);
+        c.unacked.extend_from_slice(&buf[..n]);
+        nwrite += n;
+    }
+    Some(Ok(nwrite))
+}
+
+/// Like `try_read`, taking the next received chunk off `c` instead of copying it.
+fn try_recv_bytes(c: &mut tcp::Connection) -> Option<io::Result<Bytes>> {
+    if let Err(e) = c.check_error() {
+        return Some(Err(e));
+    }
+
+    if c.rd_shutdown || (c.is_rcv_closed() && c.incoming.is_empty()) {
+        return Some(Ok(Bytes::new()));
+    }
+
+    c.incoming.pop_chunk(usize::MAX).map(Ok)
+}
+
+/// Like `try_write`, queueing the front of `data` that fits as is and leaving the rest in `data`.
+fn try_send_bytes(c: &mut tcp::Connection, data: &mut Bytes) -> Option<io::Result<usize>> {
+    if let Err(e) = c.check_error() {
+        return Some(Err(e));
+    }
+
+    if c.is_snd_closed() {
+        return Some(Ok(0));
+    }
 
-        let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
-        c.unacked.extend(buf[..nwrite].iter());
-        return Ok(nwrite);
+    if c.unacked.len() >= c.buffers.send {
+        return None;
+    }
+
+    let nwrite = std::cmp::min(data.len(), c.buffers.send - c.unacked.len());
+    c.unacked.push(data.split_to(nwrite));
+    Some(Ok(nwrite))
+}
 
-        // cm = self.h.send_var.wait(cm).unwrap();
+/// `None` while the peer has not acknowledged everything written to `c`.
+fn try_flush(c: &mut tcp::Connection) -> Option<io::Result<()>> {
+    if let Err(e) = c.check_error() {
+        return Some(Err(e));
     }
 
+    if !c.unacked.is_empty() {
+        return None;
+    }
+    Some(Ok(()))
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        (&*self).read_vectored(bufs)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        (&*self).write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// Reading and writing through a shared reference, so one thread can read while another writes.
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(&mut [io::IoSliceMut::new(buf)], false)
+    }
+
+    /// Fills `bufs` in order straight from the receive queue.
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        self.recv(bufs, false)
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(&[io::IoSlice::new(buf)])
+    }
+
+    /// Queues as much of `bufs` in order as the send buffer has room for.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.send(bufs)
+    }
+
+    /// Blocks until everything written so far has been acknowledged by the peer.
     fn flush(&mut self) -> io::Result<()> {
         let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-            io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated")
-        })?;
+        let deadline = cm
+            .connections
+            .get(&self.quad)
+            .and_then(|c| c.write_timeout)
+            .and_then(deadline);
+        loop {
+            let c = cm.connection(&self.quad)?;
+            if let Some(r) = try_flush(c) {
+                return r;
+            }
+
+            if c.nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "unacknowledged bytes buffered",
+                ));
+            }
 
-        if c.unacked.is_empty() {
-            Ok(())
-        } else {
-            // TODO: block
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ))
+            let var = c.write_var.clone();
+            cm = wait(&var, cm, deadline)?;
         }
     }
 }
 
 impl TcpStream {
-    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+    /// Like `read`, but leaves the data in the receive queue for the next read.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(&mut [io::IoSliceMut::new(buf)], true)
+    }
+
+    /// Blocks until data can be copied into `bufs`, see `try_read_vectored`.
+    fn recv(&self, bufs: &mut [io::IoSliceMut<'_>], peek: bool) -> io::Result<usize> {
+        self.wait_readable(|c| try_read_vectored(c, bufs, peek))
+    }
+
+    /// Blocks until some of `bufs` fits into the send queue.
+    fn send(&self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.wait_writable(|c| try_write_vectored(c, bufs))
+    }
+
+    /// Queues all of `data` without copying it, waiting for room in the send
+    /// buffer like `write_all`. `data` is advanced past what was queued, so on
+    /// an error, such as `WouldBlock` or `TimedOut`, it holds the unsent rest.
+    pub fn send_bytes(&self, data: &mut Bytes) -> io::Result<()> {
+        while !data.is_empty() {
+            if self.wait_writable(|c| try_send_bytes(c, data))? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "stream was shut down for writing",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `read`, but hands out the next chunk of received data without
+    /// copying it. An empty chunk means the peer closed its side.
+    pub fn recv_bytes(&self) -> io::Result<Bytes> {
+        self.wait_readable(try_recv_bytes)
+    }
+
+    /// Runs `op` until it returns something, waiting for the connection to become readable.
+    fn wait_readable<T>(
+        &self,
+        mut op: impl FnMut(&mut tcp::Connection) -> Option<io::Result<T>>,
+    ) -> io::Result<T> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let deadline = cm
+            .connections
+            .get(&self.quad)
+            .and_then(|c| c.read_timeout)
+            .and_then(deadline);
+
+        loop {
+            let c = cm.connection(&self.quad)?;
+            if let Some(r) = op(c) {
+                return r;
+            }
+
+            if c.nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no data available",
+                ));
+            }
+
+            let var = c.read_var.clone();
+            cm = wait(&var, cm, deadline)?;
+        }
+    }
+
+    /// Runs `op` until it returns something, waiting for room in the send queue.
+    fn wait_writable<T>(
+        &self,
+        mut op: impl FnMut(&mut tcp::Connection) -> Option<io::Result<T>>,
+    ) -> io::Result<T> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let deadline = cm
+            .connections
+            .get(&self.quad)
+            .and_then(|c| c.write_timeout)
+            .and_then(deadline);
+        loop {
+            let c = cm.connection(&self.quad)?;
+            if let Some(r) = op(c) {
+                return r;
+            }
+
+            if c.nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "too many bytes buffered",
+                ));
+            }
+
+            // send queue is full, wait for the peer to ack some of it
+            let var = c.write_var.clone();
+            cm = wait(&var, cm, deadline)?;
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddrV4> {
+        Ok(self.quad.peer())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        Ok(self.quad.local())
+    }
+
+    /// In non-blocking mode `read`, `write` and `flush` return `WouldBlock` instead of waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        c.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// `read` fails with `TimedOut` after waiting `timeout` for data, `None` waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        c.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// `write` and `flush` fail with `TimedOut` after waiting `timeout` for the peer's ACKs.
+    pub fn set_write_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        c.write_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        Ok(c.read_timeout)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<time::Duration>> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        Ok(c.write_timeout)
+    }
+
+    /// Bytes written but not yet acknowledged by the peer that are kept around,
+    /// `write` blocks once that many are queued.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        check_buffer_size(size)?;
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        c.buffers.send = size;
+        // a bigger buffer may have room for blocked writers
+        c.write_var.notify_all();
+        c.write_wakers.drain(..).for_each(Waker::wake);
+        Ok(())
+    }
+
+    /// Bytes received but not yet read that are kept around; the free part is
+    /// the window advertised to the peer.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        check_buffer_size(size)?;
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        c.buffers.recv = size;
+        Ok(())
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        Ok(c.buffers.send)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        Ok(c.buffers.recv)
+    }
+
+    /// Like `SO_LINGER`: with `Some(timeout)` dropping the stream blocks until the
+    /// peer acknowledged our FIN or `timeout` passed, `Some(Duration::ZERO)`
+    /// resets the connection instead of closing it. `None`, the default, closes
+    /// in the background.
+    pub fn set_linger(&self, linger: Option<time::Duration>) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        c.linger = linger;
+        Ok(())
+    }
+
+    pub fn linger(&self) -> io::Result<Option<time::Duration>> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        Ok(c.linger)
+    }
+
+    /// Probes the peer once the connection was idle for a while and fails it with
+    /// `TimedOut` when the probes go unanswered. `None`, the default, never probes.
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        check_keepalive(keepalive)?;
         let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-            io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated")
-        })?;
+        let c = cm.connection(&self.quad)?;
+
+        c.keepalive = keepalive;
+        Ok(())
+    }
+
+    pub fn keepalive(&self) -> io::Result<Option<Keepalive>> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        Ok(c.keepalive)
+    }
+
+    /// State and counters of the connection, for debugging stalls.
+    pub fn info(&self) -> io::Result<TcpInfo> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+        Ok(c.info())
+    }
+
+    /// Another handle to the same connection; the connection closes once all of them are dropped.
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+        c.handles += 1;
+        Ok(TcpStream {
+            quad: self.quad,
+            h: self.h.clone(),
+        })
+    }
+
+    /// Borrows the stream as a reading and a writing half that can be used from different threads.
+    pub fn split(&self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        (ReadHalf { stream: self }, WriteHalf { stream: self })
+    }
+
+    /// Like `split`, but the halves own the stream. Dropping the write half shuts
+    /// down writing, the connection closes once both are dropped.
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        let stream = Arc::new(self);
+        (
+            OwnedReadHalf {
+                stream: stream.clone(),
+            },
+            OwnedWriteHalf { stream },
+        )
+    }
+
+    /// Like `std::net::TcpStream::shutdown`: shutting down a direction twice is
+    /// fine, but `NotConnected` once the connection is closed or was reset.
+    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        let polled = self.h.manager.lock().unwrap().shutdown(&self.quad, how)?;
+        if polled {
+            self.h.poll_var.notify_all();
+        }
+        Ok(())
+    }
+}
+
+/// The reading half of a stream borrowed by `TcpStream::split`.
+pub struct ReadHalf<'a> {
+    stream: &'a TcpStream,
+}
+
+impl ReadHalf<'_> {
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.peek(buf)
+    }
+}
+
+impl Read for ReadHalf<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        self.stream.read_vectored(bufs)
+    }
+}
+
+/// The writing half of a stream borrowed by `TcpStream::split`.
+pub struct WriteHalf<'a> {
+    stream: &'a TcpStream,
+}
+
+impl Write for WriteHalf<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.stream.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// The reading half of a stream split by `TcpStream::into_split`.
+pub struct OwnedReadHalf {
+    stream: Arc<TcpStream>,
+}
+
+impl OwnedReadHalf {
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.peek(buf)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddrV4> {
+        self.stream.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        self.stream.local_addr()
+    }
+}
+
+impl Read for OwnedReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.stream).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        (&*self.stream).read_vectored(bufs)
+    }
+}
+
+/// The writing half of a stream split by `TcpStream::into_split`.
+pub struct OwnedWriteHalf {
+    stream: Arc<TcpStream>,
+}
+
+impl Drop for OwnedWriteHalf {
+    /// Sends a FIN once the queued data is out, the read half keeps receiving.
+    fn drop(&mut self) {
+        // fails if the connection is already gone or shut down, nothing to do then
+        let _ = self.stream.shutdown(std::net::Shutdown::Write);
+    }
+}
+
+impl OwnedWriteHalf {
+    pub fn peer_addr(&self) -> io::Result<SocketAddrV4> {
+        self.stream.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        self.stream.local_addr()
+    }
+}
+
+impl Write for OwnedWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.stream).write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        (&*self.stream).write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.stream).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+
+    fn wildcard(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)
+    }
+
+    fn local(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(DEFAULT_LOCAL_ADDR, port)
+    }
+
+    fn reuse_addr() -> BindOptions {
+        BindOptions {
+            reuse_addr: true,
+            ..Default::default()
+        }
+    }
+
+    fn reuse_port() -> BindOptions {
+        BindOptions {
+            reuse_port: true,
+            ..Default::default()
+        }
+    }
+
+    fn quad(peer_port: u16, local: SocketAddrV4) -> Quad {
+        Quad {
+            src: (PEER, peer_port),
+            dst: (*local.ip(), local.port()),
+        }
+    }
+
+    fn in_use(r: io::Result<(usize, SocketAddrV4)>) -> bool {
+        matches!(r, Err(e) if e.kind() == io::ErrorKind::AddrInUse)
+    }
+
+    #[test]
+    fn wildcard_overlaps_every_address() {
+        let mut cm = ConnectionManager::default();
+        cm.listen(wildcard(80), BindOptions::default()).unwrap();
+        assert!(in_use(cm.listen(local(80), BindOptions::default())));
+        assert!(in_use(cm.listen(wildcard(80), BindOptions::default())));
+        cm.listen(local(81), BindOptions::default()).unwrap();
+        assert!(in_use(cm.listen(wildcard(81), BindOptions::default())));
+    }
+
+    #[test]
+    fn not_an_interface_address() {
+        let mut cm = ConnectionManager::default();
+        let e = cm
+            .listen(SocketAddrV4::new(PEER, 80), BindOptions::default())
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[test]
+    fn reuse_addr_shares_the_port_between_addresses() {
+        let mut cm = ConnectionManager::default();
+        cm.listen(wildcard(80), reuse_addr()).unwrap();
+        cm.listen(local(80), reuse_addr()).unwrap();
+        // only if everyone sets it
+        cm.listen(local(82), BindOptions::default()).unwrap();
+        assert!(in_use(cm.listen(wildcard(82), reuse_addr())));
+        // the same address takes reuse_port
+        assert!(in_use(cm.listen(local(80), reuse_addr())));
+    }
+
+    #[test]
+    fn reuse_port_shares_the_exact_address() {
+        let mut cm = ConnectionManager::default();
+        cm.listen(local(80), reuse_port()).unwrap();
+        cm.listen(local(80), reuse_port()).unwrap();
+        assert!(in_use(cm.listen(local(80), BindOptions::default())));
+        assert!(in_use(cm.listen(wildcard(80), reuse_port())));
+    }
+
+    #[test]
+    fn connections_on_the_port_take_reuse_addr() {
+        let mut cm = ConnectionManager::default();
+        let q = quad(54321, local(80));
+        let c = tcp::Connection::connect(cm.clock.clone(), q.dst, q.src);
+        cm.connections.insert(q, c);
+
+        assert!(in_use(cm.listen(wildcard(80), BindOptions::default())));
+        assert!(in_use(cm.listen(local(80), BindOptions::default())));
+        cm.listen(local(80), reuse_addr()).unwrap();
+    }
+
+    #[test]
+    fn ephemeral_ports_wrap_around() {
+        let mut cm = ConnectionManager {
+            ephemeral_ports: 100..=102,
+            ..Default::default()
+        };
+        // taken by hand, so skipped
+        cm.listen(wildcard(101), BindOptions::default()).unwrap();
+
+        let (first, addr) = cm.listen(wildcard(0), BindOptions::default()).unwrap();
+        assert_eq!(addr.port(), 100);
+        let (_, addr) = cm.listen(wildcard(0), BindOptions::default()).unwrap();
+        assert_eq!(addr.port(), 102);
+        let e = cm.listen(wildcard(0), BindOptions::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrNotAvailable);
+
+        // the search starts over at the bottom of the range
+        cm.unlisten(first);
+        let (_, addr) = cm.listen(wildcard(0), BindOptions::default()).unwrap();
+        assert_eq!(addr.port(), 100);
+    }
+
+    #[test]
+    fn exact_address_beats_the_wildcard() {
+        let mut cm = ConnectionManager::default();
+        let (any, _) = cm.listen(wildcard(80), reuse_addr()).unwrap();
+        let (exact, _) = cm.listen(local(80), reuse_addr()).unwrap();
+
+        assert_eq!(find_listener(&cm.pending, &quad(1, local(80))), Some(exact));
+        let other = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+        assert_eq!(find_listener(&cm.pending, &quad(1, other)), Some(any));
+        assert_eq!(find_listener(&cm.pending, &quad(1, local(81))), None);
+
+        cm.unlisten(exact);
+        assert_eq!(find_listener(&cm.pending, &quad(1, local(80))), Some(any));
+    }
+
+    #[test]
+    fn reuse_port_spreads_peers() {
+        let mut cm = ConnectionManager::default();
+        let (a, _) = cm.listen(local(80), reuse_port()).unwrap();
+        let (b, _) = cm.listen(local(80), reuse_port()).unwrap();
 
-        c.close()
+        let picked: Vec<usize> = (1..=64)
+            .map(|port| find_listener(&cm.pending, &quad(port, local(80))).unwrap())
+            .collect();
+        assert!(picked.contains(&a) && picked.contains(&b));
+        // a peer always goes to the same one
+        assert_eq!(
+            find_listener(&cm.pending, &quad(7, local(80))),
+            Some(picked[6])
+        );
     }
 }
@@ -5,12 +5,12 @@ use std::thread;
 
 fn main() -> io::Result<()> {
     let mut i = tcpRust::Interface::new()?;
-    let mut l = i.bind(9000)?;
+    let l = i.bind(9000)?;
 
     let jh = thread::spawn(move || {
         while let Ok(mut stream) = l.accept() {
             eprintln!("got connection on 9000!!");
-            stream.write(b"hello\n").unwrap();
+            stream.write_all(b"hello\n").unwrap();
             stream.shutdown(std::net::Shutdown::Write).unwrap();
 
             loop {
@@ -33,6 +33,7 @@ fn main() -> io::Result<()> {
     Ok(())
 }
 
+#[allow(dead_code)]
 fn read_user_confirmation() -> io::Result<String> {
     let file = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
     let mut buf_reader = BufReader::new(file);
@@ -0,0 +1,363 @@
+//! Waiting on several sockets of one `Interface` at once, like epoll.
+//!
+//! Sockets are registered with a `Token` and an interest in `Available`
+//! flags; `Poll::poll` blocks until at least one of them is ready and reports
+//! which ones through `Events`.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time;
+
+use crate::tcp::Available;
+use crate::{wait, ConnectionManager, Device, Interface, InterfaceHandle, Quad, Wakeup};
+
+/// Identifies a registered socket in the events `poll` returns.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Token(pub usize);
+
+/// When a registered socket is reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    /// by every `poll` as long as the socket is ready
+    Level,
+    /// by the next `poll` after the stack made the socket ready, then not until it does again
+    Edge,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    token: Token,
+    readiness: Available,
+}
+
+impl Event {
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    pub fn readiness(&self) -> Available {
+        self.readiness
+    }
+
+    /// Data, a FIN or an error is waiting; for listeners a connection is waiting to be accepted.
+    pub fn is_readable(&self) -> bool {
+        self.readiness.contains(Available::READ)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.readiness.contains(Available::WRITE)
+    }
+}
+
+/// Events filled in by `Poll::poll`, at most `capacity` at a time.
+#[derive(Debug)]
+pub struct Events {
+    inner: Vec<Event>,
+    capacity: usize,
+}
+
+impl Events {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Events {
+            inner: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Event> {
+        self.inner.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+}
+
+impl<'a> IntoIterator for &'a Events {
+    type Item = &'a Event;
+    type IntoIter = std::slice::Iter<'a, Event>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+mod private {
+    /// The socket a registration refers to.
+    #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+    pub enum Id {
+        Connection(crate::Quad),
+        Listener(usize),
+    }
+
+    pub trait Sealed {
+        fn id(&self) -> Id;
+        /// Identifies the interface the socket belongs to.
+        fn interface(&self) -> *const ();
+    }
+}
+
+use private::Id;
+
+/// A socket that can be registered with a `Poll`: `TcpStream` or `TcpListener`.
+pub trait Source: private::Sealed {}
+
+impl private::Sealed for crate::TcpStream {
+    fn id(&self) -> Id {
+        Id::Connection(self.quad)
+    }
+
+    fn interface(&self) -> *const () {
+        Arc::as_ptr(&self.h).cast()
+    }
+}
+
+impl Source for crate::TcpStream {}
+
+impl private::Sealed for crate::TcpListener {
+    fn id(&self) -> Id {
+        Id::Listener(self.id)
+    }
+
+    fn interface(&self) -> *const () {
+        Arc::as_ptr(&self.h).cast()
+    }
+}
+
+impl Source for crate::TcpListener {}
+
+struct Registration {
+    token: Token,
+    interest: Available,
+    trigger: Trigger,
+    // readiness the stack reported since the last poll, for edge-triggered registrations
+    edges: Available,
+}
+
+/// The sockets registered with one `Poll`.
+#[derive(Default)]
+pub(crate) struct Registrations {
+    map: HashMap<Id, Registration>,
+    // where the next poll starts looking, so a full `Events` does not starve the same sockets
+    start: usize,
+}
+
+pub struct Poll {
+    id: usize,
+    h: InterfaceHandle,
+}
+
+impl Drop for Poll {
+    fn drop(&mut self) {
+        self.h.manager.lock().unwrap().polls.remove(&self.id);
+    }
+}
+
+impl Poll {
+    pub fn new<D: Device + Send + 'static>(interface: &Interface<D>) -> Self {
+        let h = interface.ih.as_ref().unwrap().clone();
+        let mut cm = h.manager.lock().unwrap();
+        let id = cm.next_poll;
+        cm.next_poll += 1;
+        cm.polls.insert(id, Registrations::default());
+        drop(cm);
+        Poll { id, h }
+    }
+
+    /// Reports `source` under `token` whenever it becomes ready for something in `interest`.
+    pub fn register<S: Source>(
+        &self,
+        source: &S,
+        token: Token,
+        interest: Available,
+        trigger: Trigger,
+    ) -> io::Result<()> {
+        self.check_source(source, interest)?;
+        let mut cm = self.h.manager.lock().unwrap();
+        let id = source.id();
+        if cm.polls[&self.id].map.contains_key(&id) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "socket already registered",
+            ));
+        }
+        cm.register(self.id, id, token, interest, trigger);
+        Ok(())
+    }
+
+    /// Replaces the token, interest and trigger of an already registered `source`.
+    pub fn reregister<S: Source>(
+        &self,
+        source: &S,
+        token: Token,
+        interest: Available,
+        trigger: Trigger,
+    ) -> io::Result<()> {
+        self.check_source(source, interest)?;
+        let mut cm = self.h.manager.lock().unwrap();
+        let id = source.id();
+        if !cm.polls[&self.id].map.contains_key(&id) {
+            return Err(not_registered());
+        }
+        cm.register(self.id, id, token, interest, trigger);
+        Ok(())
+    }
+
+    pub fn deregister<S: Source>(&self, source: &S) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let registrations = cm.polls.get_mut(&self.id).unwrap();
+        registrations
+            .map
+            .remove(&source.id())
+            .map(|_| ())
+            .ok_or_else(not_registered)
+    }
+
+    /// Waits until a registered socket is ready or `timeout` passed, filling `events`.
+    ///
+    /// `events` is empty if the timeout passed, a zero timeout only checks once.
+    pub fn poll(&mut self, events: &mut Events, timeout: Option<time::Duration>) -> io::Result<()> {
+        events.clear();
+        let deadline = timeout.and_then(crate::deadline);
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            cm.collect_events(self.id, events);
+            if !events.is_empty() {
+                return Ok(());
+            }
+
+            cm = match wait(&self.h.poll_var, cm, deadline) {
+                Ok(cm) => cm,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    fn check_source<S: Source>(&self, source: &S, interest: Available) -> io::Result<()> {
+        if source.interface() != Arc::as_ptr(&self.h).cast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "socket belongs to another interface",
+            ));
+        }
+        if interest.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot register with an empty interest",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn not_registered() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "socket not registered")
+}
+
+impl ConnectionManager {
+    /// What `id` is ready for right now.
+    fn readiness(&self, id: Id) -> Available {
+        match id {
+            // a connection that is gone is ready, so its owner finds out from read or write
+            Id::Connection(q) => self
+                .connections
+                .get(&q)
+                .map_or(Available::all(), |c| c.availability()),
+            Id::Listener(id) => match self.pending.get(&id) {
+                Some(l) if !l.queue.is_empty() => Available::READ,
+                // accept fails once the interface is down
+                Some(_) if self.check_running().is_err() => Available::READ,
+                _ => Available::empty(),
+            },
+        }
+    }
+
+    fn register(
+        &mut self,
+        poll: usize,
+        id: Id,
+        token: Token,
+        interest: Available,
+        trigger: Trigger,
+    ) {
+        // a socket that is already ready is reported right away in either mode
+        let edges = self.readiness(id) & interest;
+        self.polls.get_mut(&poll).unwrap().map.insert(
+            id,
+            Registration {
+                token,
+                interest,
+                trigger,
+                edges,
+            },
+        );
+    }
+
+    /// Remembers what `wakeup` made available for edge-triggered registrations,
+    /// returns whether any `Poll` has the socket registered.
+    pub(crate) fn mark_ready(&mut self, wakeup: &Wakeup) -> bool {
+        let (id, a) = match *wakeup {
+            Wakeup::Connection(q, a) => (Id::Connection(q), a),
+            Wakeup::Pending(id) => (Id::Listener(id), Available::READ),
+        };
+        let mut registered = false;
+        for registrations in self.polls.values_mut() {
+            if let Some(r) = registrations.map.get_mut(&id) {
+                r.edges |= a & r.interest;
+                registered = true;
+            }
+        }
+        registered
+    }
+
+    /// Drops the registrations of a connection the stack no longer keeps.
+    pub(crate) fn forget_connection(&mut self, q: &Quad) {
+        for registrations in self.polls.values_mut() {
+            registrations.map.remove(&Id::Connection(*q));
+        }
+    }
+
+    /// Drops the registrations of a listener that was closed.
+    pub(crate) fn forget_listener(&mut self, id: usize) {
+        for registrations in self.polls.values_mut() {
+            registrations.map.remove(&Id::Listener(id));
+        }
+    }
+
+    fn collect_events(&mut self, poll: usize, events: &mut Events) {
+        let mut registrations = std::mem::take(self.polls.get_mut(&poll).unwrap());
+        let ids: Vec<Id> = registrations.map.keys().copied().collect();
+        for k in 0..ids.len() {
+            let i = (registrations.start + k) % ids.len();
+            if events.inner.len() == events.capacity {
+                // the sockets left out go first next time
+                registrations.start = i;
+                break;
+            }
+
+            let id = ids[i];
+            let r = registrations.map.get_mut(&id).unwrap();
+            let readiness = match r.trigger {
+                Trigger::Level => self.readiness(id) & r.interest,
+                Trigger::Edge => std::mem::replace(&mut r.edges, Available::empty()),
+            };
+            if !readiness.is_empty() {
+                events.inner.push(Event {
+                    token: r.token,
+                    readiness,
+                });
+            }
+        }
+        self.polls.insert(poll, registrations);
+    }
+}
@@ -0,0 +1,314 @@
+//! Deterministic, single-threaded driver for the stack.
+//!
+//! `Simulation` owns the same connection state `Interface` does, but instead
+//! of a tun device and a background thread it takes packets through `inject`
+//! and only moves time forward through `advance`. Timers run against a
+//! `VirtualClock`, so minutes of protocol time pass in a few milliseconds and
+//! every run produces the same segments.
+//!
+//! `link` wires two `Interface`s together instead, to exercise the socket API
+//! with real packet loops.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::sync::{mpsc, Arc};
+use std::time;
+
+use crate::clock::VirtualClock;
+use crate::device::Device;
+use crate::{BindOptions, ConnectionManager, Keepalive, Quad, Statistics, TICK};
+
+pub mod script;
+
+/// A segment the stack sent, stamped with the virtual time it was sent at.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub at: time::Duration,
+    pub bytes: Vec<u8>,
+}
+
+struct SimDevice {
+    clock: VirtualClock,
+    sent: VecDeque<Frame>,
+}
+
+impl Device for SimDevice {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.push_back(Frame {
+            at: self.clock.elapsed(),
+            bytes: buf.to_vec(),
+        });
+        Ok(buf.len())
+    }
+
+    /// Packets come in through `Simulation::inject` instead.
+    fn recv(&mut self, _buf: &mut [u8], _timeout: time::Duration) -> io::Result<Option<usize>> {
+        Ok(None)
+    }
+}
+
+/// One end of an in-memory link between two interfaces, see `link`.
+pub struct LinkDevice {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+/// Two devices wired to each other, so a pair of `Interface::with_device`s can
+/// talk without tun devices: what one sends, the other receives.
+pub fn link() -> (LinkDevice, LinkDevice) {
+    let (a_tx, b_rx) = mpsc::channel();
+    let (b_tx, a_rx) = mpsc::channel();
+    (
+        LinkDevice { tx: a_tx, rx: a_rx },
+        LinkDevice { tx: b_tx, rx: b_rx },
+    )
+}
+
+impl Device for LinkDevice {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // with the other end gone the packet is lost, like on a cut wire
+        let _ = self.tx.send(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: time::Duration) -> io::Result<Option<usize>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(packet) => {
+                let n = std::cmp::min(buf.len(), packet.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok(Some(n))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                std::thread::sleep(timeout);
+                Ok(None)
+            }
+        }
+    }
+}
+
+pub struct Simulation {
+    clock: VirtualClock,
+    manager: ConnectionManager,
+    device: SimDevice,
+    // virtual time the timers run next, ticks keep to it however time is advanced
+    next_tick: time::Duration,
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        let clock = VirtualClock::new();
+        Simulation {
+            manager: ConnectionManager {
+                clock: Arc::new(clock.clone()),
+                ..Default::default()
+            },
+            device: SimDevice {
+                clock: clock.clone(),
+                sent: VecDeque::new(),
+            },
+            clock,
+            next_tick: TICK,
+        }
+    }
+
+    pub fn clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+
+    /// Virtual time passed since the simulation started.
+    pub fn elapsed(&self) -> time::Duration {
+        self.clock.elapsed()
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        self.manager.stats
+    }
+
+    /// Listens on `port` of every address, like `Interface::bind`.
+    pub fn listen(&mut self, port: u16) -> io::Result<()> {
+        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+        self.manager.listen(addr, BindOptions::default())?;
+        Ok(())
+    }
+
+    /// The listener on `port`; the simulation keeps one per port.
+    fn listener(&self, port: u16) -> Option<usize> {
+        self.manager
+            .pending
+            .iter()
+            .find(|(_, l)| l.addr.port() == port)
+            .map(|(&id, _)| id)
+    }
+
+    /// Like `TcpListener::set_recv_buffer_size`.
+    pub fn set_recv_buffer_size(&mut self, port: u16, size: usize) -> io::Result<()> {
+        let listener = self
+            .listener(port)
+            .and_then(|id| self.manager.pending.get_mut(&id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not listening"))?;
+        listener.buffers.recv = size;
+        Ok(())
+    }
+
+    /// Like dropping the `TcpListener`: connections not accepted yet get reset.
+    pub fn unlisten(&mut self, port: u16) {
+        if let Some(id) = self.listener(port) {
+            self.manager.unlisten(id)
+        }
+    }
+
+    /// Like `Interface::shutdown`, without waiting: `advance` drives the close.
+    pub fn shutdown(&mut self, timeout: time::Duration) {
+        let deadline = self.manager.clock.now().checked_add(timeout);
+        self.manager.start_shutdown(deadline);
+    }
+
+    /// Next connection waiting on `port`, like `TcpListener::accept` without blocking.
+    pub fn accept(&mut self, port: u16) -> Option<Quad> {
+        let id = self.listener(port)?;
+        self.manager.pending.get_mut(&id)?.queue.pop_front()
+    }
+
+    /// Opens a connection to `addr`, the SYN goes out on the next tick.
+    pub fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Quad> {
+        let quad = Quad {
+            src: (*addr.ip(), addr.port()),
+            dst: (self.manager.local_addr, self.manager.ephemeral_port()?),
+        };
+        let c = crate::tcp::Connection::connect(self.manager.clock.clone(), quad.dst, quad.src);
+        self.manager.connections.insert(quad, c);
+        Ok(quad)
+    }
+
+    /// Hands a raw IPv4 packet to the stack as if it came from the device.
+    pub fn inject(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.manager.on_packet(&mut self.device, packet);
+        Ok(())
+    }
+
+    /// Moves virtual time forward, running the timers every `TICK` on the way.
+    ///
+    /// Ticks fall on multiples of `TICK` since the start, so many short steps
+    /// run the timers as often as one long one.
+    pub fn advance(&mut self, by: time::Duration) -> io::Result<()> {
+        let until = self.clock.elapsed() + by;
+        while self.next_tick <= until {
+            // the packet loop checks on a shutdown every iteration
+            self.manager.shutdown_done();
+            self.clock.advance(self.next_tick - self.clock.elapsed());
+            self.manager.on_tick(&mut self.device);
+            self.next_tick += TICK;
+        }
+        self.clock.advance(until - self.clock.elapsed());
+        Ok(())
+    }
+
+    /// Segments sent since the last call.
+    pub fn take_sent(&mut self) -> Vec<Frame> {
+        self.device.sent.drain(..).collect()
+    }
+
+    /// Like `read` on a non-blocking `TcpStream`.
+    pub fn read(&mut self, quad: Quad, buf: &mut [u8]) -> io::Result<usize> {
+        let c = self.manager.connection(&quad)?;
+        crate::try_read(c, buf).unwrap_or_else(would_block)
+    }
+
+    /// Like `write` on a non-blocking `TcpStream`; the data goes out on the next tick.
+    pub fn write(&mut self, quad: Quad, buf: &[u8]) -> io::Result<usize> {
+        let c = self.manager.connection(&quad)?;
+        crate::try_write(c, buf).unwrap_or_else(would_block)
+    }
+
+    /// Like `TcpStream::shutdown`.
+    pub fn close(&mut self, quad: Quad, how: Shutdown) -> io::Result<()> {
+        self.manager.shutdown(&quad, how)?;
+        Ok(())
+    }
+
+    /// Like dropping the `TcpStream`: closes `quad` in the background.
+    ///
+    /// The simulation cannot block, so a linger is not waited for.
+    pub fn drop_stream(&mut self, quad: Quad) -> io::Result<()> {
+        if self.manager.connection(&quad)?.orphaned {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stream already dropped",
+            ));
+        }
+        self.manager.release(&quad);
+        Ok(())
+    }
+
+    /// Like dropping a `TcpStream` with a zero linger: the next tick resets `quad`.
+    pub fn reset(&mut self, quad: Quad) -> io::Result<()> {
+        self.manager.connection(&quad)?.linger = Some(time::Duration::ZERO);
+        self.drop_stream(quad)
+    }
+
+    /// Like `TcpStream::set_keepalive`.
+    pub fn set_keepalive(&mut self, quad: Quad, keepalive: Option<Keepalive>) -> io::Result<()> {
+        crate::check_keepalive(keepalive)?;
+        self.manager.connection(&quad)?.keepalive = keepalive;
+        Ok(())
+    }
+
+    /// Whether the stack still keeps state for `quad`.
+    pub fn is_open(&self, quad: Quad) -> bool {
+        self.manager.connections.contains_key(&quad)
+    }
+}
+
+fn would_block<T>() -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::WouldBlock,
+        "operation would block",
+    ))
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_advances_add_up_to_ticks() {
+        let mut sim = Simulation::new();
+        sim.connect(SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 80))
+            .unwrap();
+        for _ in 0..100 {
+            sim.advance(TICK / 2).unwrap();
+        }
+        let sent = sim.take_sent();
+        assert!(!sent.is_empty());
+        assert_eq!(sent[0].at, TICK);
+    }
+
+    #[test]
+    fn connections_send_in_the_same_order_every_run() {
+        let run = || {
+            let mut sim = Simulation::new();
+            for port in 80..84 {
+                sim.connect(SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), port))
+                    .unwrap();
+            }
+            sim.advance(TICK).unwrap();
+            sim.take_sent()
+                .into_iter()
+                .map(|f| f.bytes)
+                .collect::<Vec<_>>()
+        };
+        let first = run();
+        assert_eq!(first.len(), 4);
+        for _ in 0..5 {
+            assert_eq!(run(), first);
+        }
+    }
+}
@@ -0,0 +1,596 @@
+//! packetdrill-style conformance scripts.
+//!
+//! A script is a list of timed events run against one connection in a
+//! `Simulation`. Each line starts with a time in seconds, either absolute or
+//! relative to the previous line (`+0.1`), followed by an event:
+//!
+//! ```
+//! # tcpRust::sim::script::Script::parse(r#"
+//! // comments start with `//` or `#`
+//! 0.000 listen 8080
+//! 0.100 < S 1000:1000(0) win 4096       // inject a segment from the peer
+//! 0.100 > S. 0:0(0) ack 1001            // expect a segment from the stack
+//! 0.200 < . 1001:1001(0) ack 1
+//! 0.200 accept
+//! +0.1  < P. 1001:1006(5) ack 1         // 5 bytes of payload
+//! +0    > . 1:1(0) ack 1006
+//! +0    read 5                          // the application reads 5 bytes
+//! +0    read TimedOut                   // or fails with that io::ErrorKind
+//! +0    read WouldBlock                 // reads never block, like a non-blocking stream
+//! +0    write 10                        // the application writes 10 bytes
+//! +0    close                           // shut down writing, or `close read` / `close both`
+//! +0    unlisten                        // drop the listener
+//! +0    rcvbuf 8                        // receive buffer size of connections accepted later
+//! +0    drop                            // drop the stream, closing in the background
+//! +0    reset                           // drop it with a zero linger, sending a RST
+//! +60   gone                            // the stack has forgotten the connection
+//! +0    shutdown 1                      // shut the interface down with a 1s grace period
+//! +0    keepalive 10 5 3                // probe after 10s idle, every 5s, give up after 3
+//! # "#).unwrap();
+//! ```
+//!
+//! Segments are written as `<flags> <seq>:<end>(<len>) [ack <n>] [win <n>]`,
+//! where flags are any of `S`, `F`, `R`, `P` and `.` for ACK. The peer is
+//! 192.168.0.1:54321 and the stack is 192.168.0.2 on the port passed to
+//! `listen`, or on the ephemeral port picked by `connect`, which opens a
+//! connection to the peer instead of waiting for one. Expected segments must
+//! have been sent at the line's time and are compared field by field; `ack`
+//! and `win` are only compared when given.
+//! Any segment the stack sends that the script does not expect fails the run.
+//!
+//! Injected segments may add `csum bad-ip` or `csum bad-tcp` to corrupt the
+//! respective checksum.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::path::Path;
+use std::time;
+
+use super::{Frame, Simulation};
+use crate::{Keepalive, Quad};
+
+const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const REMOTE_PORT: u16 = 54321;
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+const DEFAULT_WINDOW: u16 = 65535;
+
+/// How far from the scripted time an expected segment may have been sent.
+const TOLERANCE: time::Duration = time::Duration::from_millis(4);
+
+#[derive(Debug, PartialEq)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Segment {
+    syn: bool,
+    fin: bool,
+    rst: bool,
+    psh: bool,
+    ack: bool,
+    seq: u32,
+    len: u32,
+    ackn: Option<u32>,
+    win: Option<u16>,
+    corrupt: Option<Checksum>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Checksum {
+    Ip,
+    Tcp,
+}
+
+#[derive(Debug)]
+enum Action {
+    Segment(Direction, Segment),
+    Listen(u16),
+    Unlisten,
+    RecvBuffer(usize),
+    Accept,
+    Connect,
+    Read(Expect),
+    Write(usize),
+    Close(Shutdown),
+    Drop,
+    Reset,
+    Gone,
+    Shutdown(time::Duration),
+    Keepalive(Keepalive),
+}
+
+/// What a `read` should come back with: a byte count or the kind of error, like `TimedOut`.
+#[derive(Debug)]
+enum Expect {
+    Bytes(usize),
+    Error(String),
+}
+
+#[derive(Debug)]
+struct Event {
+    line: usize,
+    at: time::Duration,
+    action: Action,
+}
+
+#[derive(Debug)]
+pub struct Script {
+    events: Vec<Event>,
+}
+
+fn error(line: usize, msg: impl fmt::Display) -> io::Error {
+    io::Error::other(format!("line {}: {}", line, msg))
+}
+
+fn parse_error(line: usize, msg: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("line {}: {}", line, msg),
+    )
+}
+
+fn parse_num<T: std::str::FromStr>(line: usize, s: &str) -> io::Result<T> {
+    s.parse()
+        .map_err(|_| parse_error(line, format!("bad number {:?}", s)))
+}
+
+fn parse_time(line: usize, s: &str) -> io::Result<time::Duration> {
+    let secs: f64 = parse_num(line, s)?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(parse_error(line, format!("bad time {:?}", s)));
+    }
+    Ok(time::Duration::from_micros((secs * 1e6).round() as u64))
+}
+
+fn parse_segment<'a>(line: usize, mut words: impl Iterator<Item = &'a str>) -> io::Result<Segment> {
+    let mut seg = Segment::default();
+
+    let flags = words
+        .next()
+        .ok_or_else(|| parse_error(line, "missing flags"))?;
+    for f in flags.chars() {
+        match f {
+            'S' => seg.syn = true,
+            'F' => seg.fin = true,
+            'R' => seg.rst = true,
+            'P' => seg.psh = true,
+            '.' => seg.ack = true,
+            _ => return Err(parse_error(line, format!("unknown flag {:?}", f))),
+        }
+    }
+
+    // <seq>:<end>(<len>)
+    let range = words
+        .next()
+        .ok_or_else(|| parse_error(line, "missing sequence range"))?;
+    let (seq, rest) = range
+        .split_once(':')
+        .ok_or_else(|| parse_error(line, format!("bad sequence range {:?}", range)))?;
+    let (end, len) = rest
+        .strip_suffix(')')
+        .and_then(|rest| rest.split_once('('))
+        .ok_or_else(|| parse_error(line, format!("bad sequence range {:?}", range)))?;
+    seg.seq = parse_num(line, seq)?;
+    seg.len = parse_num(line, len)?;
+    let end: u32 = parse_num(line, end)?;
+    if end.wrapping_sub(seg.seq) != seg.len {
+        return Err(parse_error(line, format!("{:?} does not add up", range)));
+    }
+
+    while let Some(word) = words.next() {
+        let value = words
+            .next()
+            .ok_or_else(|| parse_error(line, format!("missing value for {:?}", word)))?;
+        match word {
+            "ack" => seg.ackn = Some(parse_num(line, value)?),
+            "win" => seg.win = Some(parse_num(line, value)?),
+            "csum" => {
+                seg.corrupt = match value {
+                    "bad-ip" => Some(Checksum::Ip),
+                    "bad-tcp" => Some(Checksum::Tcp),
+                    _ => return Err(parse_error(line, format!("bad csum {:?}", value))),
+                }
+            }
+            _ => return Err(parse_error(line, format!("unknown field {:?}", word))),
+        }
+    }
+
+    if seg.ackn.is_some() && !seg.ack {
+        return Err(parse_error(line, "ack number given without the ACK flag"));
+    }
+    Ok(seg)
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, c) in [
+            (self.syn, 'S'),
+            (self.fin, 'F'),
+            (self.rst, 'R'),
+            (self.psh, 'P'),
+            (self.ack, '.'),
+        ] {
+            if set {
+                write!(f, "{}", c)?;
+            }
+        }
+        write!(
+            f,
+            " {}:{}({})",
+            self.seq,
+            self.seq.wrapping_add(self.len),
+            self.len
+        )?;
+        if let Some(ackn) = self.ackn {
+            write!(f, " ack {}", ackn)?;
+        }
+        if let Some(win) = self.win {
+            write!(f, " win {}", win)?;
+        }
+        Ok(())
+    }
+}
+
+impl Segment {
+    fn from_frame(line: usize, frame: &Frame) -> io::Result<Self> {
+        let ip_h = etherparse::Ipv4HeaderSlice::from_slice(&frame.bytes)
+            .map_err(|e| error(line, format!("stack sent a bad ip header: {:?}", e)))?;
+        let tcp_h = etherparse::TcpHeaderSlice::from_slice(&frame.bytes[ip_h.slice().len()..])
+            .map_err(|e| error(line, format!("stack sent a bad tcp header: {:?}", e)))?;
+        let headers = ip_h.slice().len() + tcp_h.slice().len();
+        if !crate::ipv4_checksum_ok(ip_h.slice()) {
+            return Err(error(line, "stack sent a bad ip checksum"));
+        }
+        if tcp_h
+            .calc_checksum_ipv4(&ip_h, &frame.bytes[headers..])
+            .ok()
+            != Some(tcp_h.checksum())
+        {
+            return Err(error(line, "stack sent a bad tcp checksum"));
+        }
+
+        Ok(Segment {
+            syn: tcp_h.syn(),
+            fin: tcp_h.fin(),
+            rst: tcp_h.rst(),
+            psh: tcp_h.psh(),
+            ack: tcp_h.ack(),
+            seq: tcp_h.sequence_number(),
+            len: (ip_h.total_len() as usize).saturating_sub(headers) as u32,
+            ackn: Some(tcp_h.acknowledgment_number()).filter(|_| tcp_h.ack()),
+            win: Some(tcp_h.window_size()),
+            corrupt: None,
+        })
+    }
+
+    fn to_packet(&self, line: usize, port: u16) -> io::Result<Vec<u8>> {
+        let mut b = etherparse::PacketBuilder::ipv4(REMOTE_ADDR.octets(), LOCAL_ADDR.octets(), 64)
+            .tcp(
+                REMOTE_PORT,
+                port,
+                self.seq,
+                self.win.unwrap_or(DEFAULT_WINDOW),
+            );
+        if self.syn {
+            b = b.syn();
+        }
+        if self.fin {
+            b = b.fin();
+        }
+        if self.rst {
+            b = b.rst();
+        }
+        if self.psh {
+            b = b.psh();
+        }
+        if self.ack {
+            b = b.ack(self.ackn.unwrap_or(0));
+        }
+
+        let payload = pattern(self.len as usize);
+        let mut packet = Vec::with_capacity(b.size(payload.len()));
+        b.write(&mut packet, &payload)
+            .map_err(|e| error(line, format!("cannot build segment: {:?}", e)))?;
+        match self.corrupt {
+            Some(Checksum::Ip) => packet[10] ^= 0xff,
+            Some(Checksum::Tcp) => packet[20 + 16] ^= 0xff,
+            None => {}
+        }
+        Ok(packet)
+    }
+
+    /// Differences between an expected and a sent segment, in script syntax.
+    fn mismatches(&self, got: &Segment) -> Vec<String> {
+        let mut diffs = Vec::new();
+        for (name, want, have) in [
+            ("syn", self.syn, got.syn),
+            ("fin", self.fin, got.fin),
+            ("rst", self.rst, got.rst),
+            ("psh", self.psh, got.psh),
+            ("ack flag", self.ack, got.ack),
+        ] {
+            if want != have {
+                diffs.push(format!("{} expected {} got {}", name, want, have));
+            }
+        }
+        if self.seq != got.seq {
+            diffs.push(format!("seq expected {} got {}", self.seq, got.seq));
+        }
+        if self.len != got.len {
+            diffs.push(format!("len expected {} got {}", self.len, got.len));
+        }
+        if let Some(ackn) = self.ackn.filter(|&ackn| got.ackn != Some(ackn)) {
+            diffs.push(format!("ack expected {} got {:?}", ackn, got.ackn));
+        }
+        if let Some(win) = self.win.filter(|&win| got.win != Some(win)) {
+            diffs.push(format!("win expected {} got {:?}", win, got.win));
+        }
+        diffs
+    }
+}
+
+/// Payload used for injected segments and application writes.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| b'a' + (i % 26) as u8).collect()
+}
+
+impl Script {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut events = Vec::new();
+        let mut last = time::Duration::ZERO;
+
+        for (i, raw) in text.lines().enumerate() {
+            let line = i + 1;
+            let content = raw.split("//").next().unwrap().split('#').next().unwrap();
+            let mut words = content.split_whitespace();
+            let when = match words.next() {
+                Some(when) => when,
+                None => continue,
+            };
+
+            let at = match when.strip_prefix('+') {
+                Some(offset) => last + parse_time(line, offset)?,
+                None => parse_time(line, when)?,
+            };
+            if at < last {
+                return Err(parse_error(line, "time goes backwards"));
+            }
+            last = at;
+
+            let what = words
+                .next()
+                .ok_or_else(|| parse_error(line, "missing event"))?;
+            let mut arg = || {
+                words
+                    .next()
+                    .ok_or_else(|| parse_error(line, format!("{} needs an argument", what)))
+            };
+            let action = match what {
+                "<" => Action::Segment(Direction::Inbound, parse_segment(line, &mut words)?),
+                ">" => Action::Segment(Direction::Outbound, parse_segment(line, &mut words)?),
+                "listen" => Action::Listen(parse_num(line, arg()?)?),
+                "unlisten" => Action::Unlisten,
+                "rcvbuf" => Action::RecvBuffer(parse_num(line, arg()?)?),
+                "accept" => Action::Accept,
+                "connect" => Action::Connect,
+                "read" => {
+                    let what = arg()?;
+                    match what.parse() {
+                        Ok(n) => Action::Read(Expect::Bytes(n)),
+                        Err(_) => Action::Read(Expect::Error(what.to_string())),
+                    }
+                }
+                "write" => Action::Write(parse_num(line, arg()?)?),
+                "close" => match words.next() {
+                    None | Some("write") => Action::Close(Shutdown::Write),
+                    Some("read") => Action::Close(Shutdown::Read),
+                    Some("both") => Action::Close(Shutdown::Both),
+                    Some(how) => return Err(parse_error(line, format!("bad shutdown {:?}", how))),
+                },
+                "drop" => Action::Drop,
+                "reset" => Action::Reset,
+                "gone" => Action::Gone,
+                "shutdown" => Action::Shutdown(parse_time(line, arg()?)?),
+                "keepalive" => Action::Keepalive(Keepalive {
+                    idle: parse_time(line, arg()?)?,
+                    interval: parse_time(line, arg()?)?,
+                    probes: parse_num(line, arg()?)?,
+                }),
+                _ => return Err(parse_error(line, format!("unknown event {:?}", what))),
+            };
+            if let Some(word) = words.next() {
+                return Err(parse_error(
+                    line,
+                    format!("unexpected {:?} after {}", word, what),
+                ));
+            }
+
+            events.push(Event { line, at, action });
+        }
+
+        Ok(Script { events })
+    }
+
+    pub fn run(&self) -> io::Result<()> {
+        let mut sim = Simulation::new();
+        let mut sent: VecDeque<Frame> = VecDeque::new();
+        let mut port = None;
+
+        for event in &self.events {
+            let line = event.line;
+            if event.at > sim.elapsed() {
+                sim.advance(event.at - sim.elapsed())
+                    .map_err(|e| error(line, e))?;
+            }
+            sent.extend(sim.take_sent());
+
+            if let Action::Segment(Direction::Outbound, want) = &event.action {
+                let frame = sent.pop_front().ok_or_else(|| {
+                    error(line, format!("expected {}, but nothing was sent", want))
+                })?;
+                let got = Segment::from_frame(line, &frame)?;
+                if frame.at.abs_diff(event.at) > TOLERANCE {
+                    return Err(error(
+                        line,
+                        format!(
+                            "expected {} at {:?}, but it was sent at {:?}",
+                            want, event.at, frame.at
+                        ),
+                    ));
+                }
+                let diffs = want.mismatches(&got);
+                if !diffs.is_empty() {
+                    return Err(error(
+                        line,
+                        format!("expected {}, got {}: {}", want, got, diffs.join(", ")),
+                    ));
+                }
+                continue;
+            }
+
+            if let Some(frame) = sent.front() {
+                return Err(error(
+                    line,
+                    format!(
+                        "unexpected segment {} sent at {:?}",
+                        Segment::from_frame(line, frame)?,
+                        frame.at
+                    ),
+                ));
+            }
+
+            let quad = |port: Option<u16>| -> io::Result<Quad> {
+                let port = port.ok_or_else(|| error(line, "nothing is listening"))?;
+                Ok(Quad {
+                    src: (REMOTE_ADDR, REMOTE_PORT),
+                    dst: (LOCAL_ADDR, port),
+                })
+            };
+            match &event.action {
+                Action::Segment(_, seg) => {
+                    let packet = seg.to_packet(line, port.unwrap_or(0))?;
+                    sim.inject(&packet).map_err(|e| error(line, e))?;
+                }
+                Action::Listen(p) => {
+                    sim.listen(*p).map_err(|e| error(line, e))?;
+                    port = Some(*p);
+                }
+                Action::Unlisten => sim.unlisten(quad(port)?.dst.1),
+                Action::RecvBuffer(size) => sim
+                    .set_recv_buffer_size(quad(port)?.dst.1, *size)
+                    .map_err(|e| error(line, e))?,
+                Action::Accept => {
+                    let q = quad(port)?;
+                    if sim.accept(q.dst.1) != Some(q) {
+                        return Err(error(line, "no connection to accept"));
+                    }
+                }
+                Action::Connect => {
+                    let q = sim
+                        .connect(SocketAddrV4::new(REMOTE_ADDR, REMOTE_PORT))
+                        .map_err(|e| error(line, e))?;
+                    port = Some(q.dst.1);
+                }
+                Action::Read(Expect::Bytes(n)) => {
+                    let mut buf = vec![0u8; *n];
+                    let nread = sim
+                        .read(quad(port)?, &mut buf)
+                        .map_err(|e| error(line, e))?;
+                    if nread != *n {
+                        return Err(error(line, format!("read {} bytes, expected {}", nread, n)));
+                    }
+                }
+                Action::Read(Expect::Error(kind)) => {
+                    let mut buf = [0u8; 1];
+                    match sim.read(quad(port)?, &mut buf) {
+                        Err(e) if format!("{:?}", e.kind()) == *kind => {}
+                        Err(e) => return Err(error(line, format!("expected {}, got {}", kind, e))),
+                        Ok(n) => {
+                            return Err(error(line, format!("expected {}, read {} bytes", kind, n)))
+                        }
+                    }
+                }
+                Action::Write(n) => {
+                    let nwrite = sim
+                        .write(quad(port)?, &pattern(*n))
+                        .map_err(|e| error(line, e))?;
+                    if nwrite != *n {
+                        return Err(error(
+                            line,
+                            format!("wrote {} bytes, expected {}", nwrite, n),
+                        ));
+                    }
+                }
+                Action::Close(how) => {
+                    let q = quad(port)?;
+                    sim.close(q, *how).map_err(|e| error(line, e))?
+                }
+                Action::Drop => sim.drop_stream(quad(port)?).map_err(|e| error(line, e))?,
+                Action::Reset => sim.reset(quad(port)?).map_err(|e| error(line, e))?,
+                Action::Shutdown(timeout) => sim.shutdown(*timeout),
+                Action::Keepalive(k) => sim
+                    .set_keepalive(quad(port)?, Some(*k))
+                    .map_err(|e| error(line, e))?,
+                Action::Gone => {
+                    if sim.is_open(quad(port)?) {
+                        return Err(error(line, "connection is still open"));
+                    }
+                }
+            }
+        }
+
+        sent.extend(sim.take_sent());
+        if let Some(frame) = sent.front() {
+            let line = self.events.last().map_or(0, |e| e.line);
+            return Err(error(
+                line,
+                format!(
+                    "unexpected segment {} sent at {:?}",
+                    Segment::from_frame(line, frame)?,
+                    frame.at
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Parses and runs the script at `path`.
+pub fn run_file(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)?;
+    Script::parse(&text)
+        .and_then(|script| script.run())
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(text: &str) -> String {
+        Script::parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn trailing_words_are_rejected() {
+        assert_eq!(
+            parse_err("0 listen 8080\n0 accept foo"),
+            "line 2: unexpected \"foo\" after accept"
+        );
+        assert_eq!(
+            parse_err("0 read 5 the application reads"),
+            "line 1: unexpected \"the\" after read"
+        );
+        assert_eq!(
+            parse_err("0 keepalive 10 5 3 4"),
+            "line 1: unexpected \"4\" after keepalive"
+        );
+        // comments are not words
+        Script::parse("0 listen 8080 // the port\n0 close both # both ways").unwrap();
+    }
+}
@@ -1,17 +1,35 @@
 use bitflags::bitflags;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::BTreeMap;
 use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Condvar};
+use std::task::Waker;
 use std::{io, time};
 
+use crate::buffer::ChunkQueue;
+use crate::clock::Clock;
+use crate::device::Device;
+use crate::Error;
+
+/// How long TIME-WAIT lasts (2*MSL), Linux's 60s rather than RFC 793's 4 minutes.
+/// An orphaned connection waits as long for the peer's FIN in FIN-WAIT-2.
+const TIME_WAIT: time::Duration = time::Duration::from_secs(60);
+
+/// Retransmissions without an ACK after which an orphaned connection gives up
+/// and resets, like Linux's `tcp_orphan_retries`.
+const ORPHAN_RETRIES: u32 = 8;
+
 bitflags! {
-    pub(crate) struct Available: u8 {
+    /// What a socket is ready for, also used as the interest of a `Poll` registration.
+    pub struct Available: u8 {
         const READ = 0b00000001;
         const WRITE = 0b00000010;
     }
 }
 
-#[derive(Debug)]
-enum State {
+/// Connection states of RFC 793, see `TcpInfo::state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
     Listen,
     SynSent,
     SynRcvd,
@@ -41,17 +59,156 @@ pub struct Connection {
     pub ip: etherparse::Ipv4Header,
     tcp: etherparse::TcpHeader,
     timers: Timers,
+    clock: Arc<dyn Clock>,
 
-    pub(crate) incoming: VecDeque<u8>, // pub(crate) == protected keyword
-    pub(crate) unacked: VecDeque<u8>,  // unacked contains both sent and unsent data
+    pub(crate) incoming: ChunkQueue, // pub(crate) == protected keyword
+    pub(crate) unacked: ChunkQueue,  // unacked contains both sent and unsent data
+    // limits of incoming and unacked
+    pub(crate) buffers: Buffers,
 
     pub(crate) closed: bool,
+    // shut down for reading: incoming data is acked but dropped
+    pub(crate) rd_shutdown: bool,
+    pub(crate) nonblocking: bool,
+    pub(crate) read_timeout: Option<time::Duration>,
+    pub(crate) write_timeout: Option<time::Duration>,
+    // threads waiting for the connection to become readable / writable,
+    // shared so a waiter can hold on to it while giving up the manager lock
+    pub(crate) read_var: Arc<Condvar>,
+    pub(crate) write_var: Arc<Condvar>,
+    // tasks waiting for the connection to become readable / writable
+    pub(crate) read_wakers: Vec<Waker>,
+    pub(crate) write_wakers: Vec<Waker>,
     closed_at: Option<u32>,
+
+    // `TcpStream`s sharing the connection, see `TcpStream::try_clone`
+    pub(crate) handles: usize,
+    // the stream was dropped, the manager removes the connection once it is closed
+    pub(crate) orphaned: bool,
+    // SO_LINGER: how long dropping the stream waits for the close to be acked, 0 resets
+    pub(crate) linger: Option<time::Duration>,
+    // an abortive close was requested, the next tick sends the RST
+    rst_pending: bool,
+    pub(crate) keepalive: Option<Keepalive>,
+    // when the peer was last heard from, and keepalive probes unanswered since
+    last_heard: time::Instant,
+    probes_sent: u32,
+    // retransmissions since the peer last acknowledged something
+    retries: u32,
+
+    // why the connection was aborted, reported to the stream instead of its data
+    error: Option<Error>,
+
+    // for `info`
+    retransmits: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// A snapshot of a connection's state, like Linux's `TCP_INFO`; see `TcpStream::info`.
+///
+/// Sequence numbers are the absolute ones on the wire.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpInfo {
+    pub state: State,
+    /// oldest sequence number not acknowledged by the peer (SND.UNA)
+    pub snd_una: u32,
+    /// next sequence number to send (SND.NXT)
+    pub snd_nxt: u32,
+    /// window the peer advertised (SND.WND)
+    pub snd_wnd: u16,
+    /// next sequence number expected from the peer (RCV.NXT)
+    pub rcv_nxt: u32,
+    /// window we advertise, the free receive buffer (RCV.WND)
+    pub rcv_wnd: u16,
+    /// smoothed round-trip time
+    pub srtt: time::Duration,
+    /// mean deviation of the round-trip time samples
+    pub rttvar: time::Duration,
+    /// how long an unacknowledged segment waits before it is retransmitted
+    pub rto: time::Duration,
+    /// congestion window, `None` as the stack does no congestion control yet
+    pub cwnd: Option<u32>,
+    /// sequence numbers sent but not acknowledged yet
+    pub bytes_in_flight: u32,
+    /// retransmission timeouts, each resending the oldest unacknowledged data
+    pub retransmits: u64,
+    /// payload bytes put on the wire, retransmissions included
+    pub bytes_sent: u64,
+    /// payload bytes received in order, whether read yet or not
+    pub bytes_received: u64,
+    /// bytes written but not acknowledged yet, sent or not
+    pub send_buffered: usize,
+    /// bytes received but not read yet
+    pub recv_buffered: usize,
+}
+
+/// Keepalive settings of a connection (RFC 1122 S4.2.3.6), see `TcpStream::set_keepalive`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// how long the connection has to be idle before the first probe
+    pub idle: time::Duration,
+    /// time between unanswered probes
+    pub interval: time::Duration,
+    /// unanswered probes after which the connection fails with `TimedOut`
+    pub probes: u32,
+}
+
+impl Default for Keepalive {
+    /// Linux's defaults: probing after two hours, nine probes 75s apart.
+    fn default() -> Self {
+        Keepalive {
+            idle: time::Duration::from_secs(2 * 60 * 60),
+            interval: time::Duration::from_secs(75),
+            probes: 9,
+        }
+    }
+}
+
+/// Send and receive buffer sizes of a connection, see `TcpStream::set_send_buffer_size`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Buffers {
+    pub(crate) send: usize,
+    pub(crate) recv: usize,
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Buffers {
+            send: crate::DEFAULT_BUFFER_SIZE,
+            recv: crate::DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+/// What blocked callers of a connection wait for, taken before and after a
+/// segment to wake only those whose condition became true.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Waiters {
+    available: Available,
+    state: State,
+    flushed: bool,
+}
+
+impl Waiters {
+    /// Readers and writers to wake going from `self` to `after`; `connect`,
+    /// `flush` and a lingering drop wait with the writers.
+    pub(crate) fn woken(&self, after: &Waiters) -> Available {
+        let mut a = after.available & !self.available;
+        if self.state != after.state || (!self.flushed && after.flushed) {
+            a |= Available::WRITE;
+        }
+        a
+    }
 }
 
 struct Timers {
     send_times: BTreeMap<u32, time::Instant>,
     srtt: f64,
+    rttvar: f64,
+    // the state seen by the last tick and since when, for TIME-WAIT and FIN-WAIT-2 timeouts
+    state: State,
+    state_since: time::Instant,
 }
 
 impl Connection {
@@ -82,14 +239,34 @@ impl Connection {
         }
     }
 
-    fn availability(&self) -> Available {
+    /// Free receive space, the window we advertise. Data is dropped once reading
+    /// is shut down, so then the whole buffer is free.
+    fn recv_window(&self) -> u16 {
+        let free = if self.rd_shutdown {
+            self.buffers.recv
+        } else {
+            self.buffers.recv.saturating_sub(self.incoming.len())
+        };
+        std::cmp::min(free, u16::MAX as usize) as u16
+    }
+
+    pub(crate) fn waiters(&self) -> Waiters {
+        Waiters {
+            available: self.availability(),
+            state: self.state,
+            flushed: self.unacked.is_empty(),
+        }
+    }
+
+    pub(crate) fn availability(&self) -> Available {
         let mut a = Available::empty();
 
-        if self.is_rcv_closed() || !self.incoming.is_empty() {
+        if self.is_rcv_closed() || self.rd_shutdown || !self.incoming.is_empty() {
             a |= Available::READ;
         }
 
-        if self.is_snd_closed() || !self.unacked.is_empty() {
+        // writers wait for room in the send queue, flushers for it to drain
+        if self.is_snd_closed() || self.unacked.len() < self.buffers.send {
             a |= Available::WRITE;
         }
 
@@ -102,7 +279,7 @@ impl Connection {
 
 /// State of the Send Sequence Space (RFC 793 S3.2 Figure4)
 ///
-/// ```
+/// ```text
 ///
 ///      1         2          3          4
 /// ----------|----------|----------|----------
@@ -125,17 +302,16 @@ pub struct SendSequenceSpace {
     // send urgent pointer
     up: bool,
     // segment sequence number used for last window update
-    wl1: usize,
+    wl1: u32,
     // segment acknowledgment number used for last window update
-    wl2: usize,
+    wl2: u32,
     // initial send sequence number
     iss: u32,
 }
 
 /// State of the Receive Sequence Space (RFC 793 S3.2 Figure5)
 ///
-/// ```
-
+/// ```text
 ///      1          2          3
 /// ----------|----------|----------
 ///        RCV.NXT    RCV.NXT
@@ -159,10 +335,12 @@ pub struct RecvSequenceSpace {
 
 impl Connection {
     pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
+        nic: &mut dyn Device,
+        clock: Arc<dyn Clock>,
         ip_h: etherparse::Ipv4HeaderSlice<'a>,
         tcp_h: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8],
+        buffers: Buffers,
     ) -> io::Result<Option<Self>> {
         let buf = [0u8; 1500];
         if !tcp_h.syn() {
@@ -171,23 +349,23 @@ impl Connection {
         }
 
         let iss = 0;
-        let wnd = 1024;
+        let wnd = std::cmp::min(buffers.recv, u16::MAX as usize) as u16;
         let mut c = Connection {
             state: State::SynRcvd,
             send: SendSequenceSpace {
                 iss: iss,
                 una: iss,
                 nxt: iss,
-                wnd: wnd,
+                wnd: tcp_h.window_size(),
                 up: false,
 
-                wl1: 0,
+                wl1: tcp_h.sequence_number(),
                 wl2: 0,
             },
             recv: RecvSequenceSpace {
                 irs: tcp_h.sequence_number(),
-                nxt: tcp_h.sequence_number() + 1,
-                wnd: tcp_h.window_size(),
+                nxt: tcp_h.sequence_number().wrapping_add(1),
+                wnd,
                 up: false,
             },
             ip: etherparse::Ipv4Header::new(
@@ -206,14 +384,39 @@ impl Connection {
 
             incoming: Default::default(),
             unacked: Default::default(),
+            buffers,
 
             closed: false,
+            rd_shutdown: false,
+            nonblocking: false,
+            read_timeout: None,
+            write_timeout: None,
+            read_var: Default::default(),
+            write_var: Default::default(),
+            read_wakers: Vec::new(),
+            write_wakers: Vec::new(),
             closed_at: None,
+            handles: 1,
+            orphaned: false,
+            linger: None,
+            rst_pending: false,
+            keepalive: None,
+            last_heard: clock.now(),
+            probes_sent: 0,
+            retries: 0,
+            retransmits: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            error: None,
 
             timers: Timers {
                 send_times: Default::default(),
                 srtt: time::Duration::from_secs(1 * 60).as_secs_f64(),
+                rttvar: time::Duration::from_secs(30).as_secs_f64(),
+                state: State::SynRcvd,
+                state_since: clock.now(),
             },
+            clock,
         };
 
         // needs to start establishing connection
@@ -223,88 +426,101 @@ impl Connection {
         Ok(Some(c))
     }
 
-    pub fn connect<'a>(
-        nic: &mut tun_tap::Iface,
-        ip_h: etherparse::Ipv4HeaderSlice<'a>,
-        tcp_h: etherparse::TcpHeaderSlice<'a>,
-        data: &'a [u8],
-    ) -> io::Result<Option<Self>> {
-        let buf = [0u8; 1500];
-        if !tcp_h.syn() {
-            // expected SYN packet
-            return Ok(None);
-        }
-
+    /// Active open towards `remote`, the SYN goes out on the next tick.
+    pub fn connect(clock: Arc<dyn Clock>, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16)) -> Self {
         let iss = 0;
-        let wnd = 1024;
+        let buffers = Buffers::default();
+        let wnd = std::cmp::min(buffers.recv, u16::MAX as usize) as u16;
         let mut c = Connection {
-            state: State::SynRcvd,
+            state: State::SynSent,
+            // the peer's window is filled in from its SYN
             send: SendSequenceSpace {
                 iss: iss,
                 una: iss,
                 nxt: iss,
-                wnd: wnd,
+                wnd: 0,
                 up: false,
 
                 wl1: 0,
                 wl2: 0,
             },
+            // filled in from the peer's SYN
             recv: RecvSequenceSpace {
-                irs: tcp_h.sequence_number(),
-                nxt: tcp_h.sequence_number() + 1,
-                wnd: tcp_h.window_size(),
+                irs: 0,
+                nxt: 0,
+                wnd,
                 up: false,
             },
             ip: etherparse::Ipv4Header::new(
                 0,
                 64,
                 etherparse::IpTrafficClass::Tcp,
-                ip_h.destination().try_into().unwrap(),
-                ip_h.source().try_into().unwrap(),
-            ),
-            tcp: etherparse::TcpHeader::new(
-                tcp_h.destination_port(),
-                tcp_h.source_port(),
-                iss,
-                wnd,
+                local.0.octets(),
+                remote.0.octets(),
             ),
+            tcp: etherparse::TcpHeader::new(local.1, remote.1, iss, wnd),
 
             incoming: Default::default(),
             unacked: Default::default(),
+            buffers,
 
             closed: false,
+            rd_shutdown: false,
+            nonblocking: false,
+            read_timeout: None,
+            write_timeout: None,
+            read_var: Default::default(),
+            write_var: Default::default(),
+            read_wakers: Vec::new(),
+            write_wakers: Vec::new(),
             closed_at: None,
+            handles: 1,
+            orphaned: false,
+            linger: None,
+            rst_pending: false,
+            keepalive: None,
+            last_heard: clock.now(),
+            probes_sent: 0,
+            retries: 0,
+            retransmits: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            error: None,
 
             timers: Timers {
                 send_times: Default::default(),
                 srtt: time::Duration::from_secs(1 * 60).as_secs_f64(),
+                rttvar: time::Duration::from_secs(30).as_secs_f64(),
+                state: State::SynSent,
+                state_since: clock.now(),
             },
+            clock,
         };
 
-        // needs to start establishing connection -- send syn on
-        c.tcp.syn = true;
         c.tcp.ack = false;
-        c.write(nic, c.send.nxt, 0)?;
-        Ok(Some(c))
+        c
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, seq: u32, mut limit: usize) -> io::Result<usize> {
+    fn write(&mut self, nic: &mut dyn Device, seq: u32, mut limit: usize) -> io::Result<usize> {
         let mut buf = [0u8; 1500];
         self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+        self.recv.wnd = self.recv_window();
+        self.tcp.window_size = self.recv.wnd;
 
         // TODO: return +1 for SYN/FIN
 
         println!(
             "write(ack: {}, seq: {}, limit: {}) syn {:?} fin {:?}",
-            self.recv.nxt - self.recv.irs,
+            self.recv.nxt.wrapping_sub(self.recv.irs),
             seq,
             limit,
             self.tcp.syn,
             self.tcp.fin,
         );
 
-        let mut offset = seq.wrapping_sub(self.send.una) as usize;
+        let mut offset =
+            std::cmp::min(seq.wrapping_sub(self.send.una) as usize, self.unacked.len());
         // we need two special case the two 'virtual' bytes SYN and FIN
         if let Some(closed_at) = self.closed_at {
             if seq == closed_at.wrapping_add(1) {
@@ -321,16 +537,7 @@ impl Connection {
         //     self.unacked.as_slices()
         // );
 
-        let (mut h, mut t) = self.unacked.as_slices();
-        if h.len() >= offset {
-            h = &h[offset..];
-        } else {
-            let skipped = h.len();
-            h = &[];
-            t = &t[(offset - skipped)..];
-        }
-
-        let mut max_data = std::cmp::min(limit, h.len() + t.len());
+        let max_data = std::cmp::min(limit, self.unacked.len() - offset);
         let size = std::cmp::min(
             buf.len(),
             self.tcp.header_len() as usize + self.ip.header_len() as usize + max_data,
@@ -351,22 +558,20 @@ impl Connection {
         unwritten = &mut unwritten[self.tcp.header_len() as usize..];
         let tcp_header_ends_at = buf_len - unwritten.len();
 
-        // write out the payload
+        // write out the payload, it may span several of the queued chunks
         let payload_bytes = {
             let mut written = 0;
-            let mut limit = max_data;
-
-            // first, write as much as we can from h
-            let p1l = std::cmp::min(limit, h.len());
-            written += unwritten.write(&h[..p1l])?;
-            limit -= written;
-
-            // then, write more (if we can) from t
-            let p2l = std::cmp::min(limit, t.len());
-            written += unwritten.write(&t[..p2l])?;
+            for chunk in self.unacked.slices(offset) {
+                if written == max_data {
+                    break;
+                }
+                let n = std::cmp::min(max_data - written, chunk.len());
+                written += unwritten.write(&chunk[..n])?;
+            }
             written
         };
         let payload_ends_at = buf_len - unwritten.len();
+        self.bytes_sent += payload_bytes as u64;
 
         // checksum calculation
         self.tcp.checksum = self
@@ -389,21 +594,14 @@ impl Connection {
         if wrapping_lt(self.send.nxt, next_seq) {
             self.send.nxt = next_seq;
         }
-        self.timers.send_times.insert(seq, time::Instant::now());
+        self.timers.send_times.insert(seq, self.clock.now());
 
         nic.send(&buf[..payload_ends_at])?;
         Ok(payload_bytes)
     }
 
-    fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        self.tcp.rst = true;
-        // TODO: fix sequence numbers here
-        // If the incoming segment has an ACK field, the reset takes its
-        // sequence number from the ACK field of the segment, otherwise the
-        // reset has sequence number zero and the ACK field is set to the sum
-        // of the sequence number and segment length of the incoming segment.
-        // The connection remains in the same state.
-        //
+    /// Resets the connection from our side: a RST at SND.NXT acknowledging RCV.NXT.
+    fn send_rst(&mut self, nic: &mut dyn Device) -> io::Result<()> {
         // TODO: handle synchronized RST
         // 3.  If the connection is in a synchronized state (ESTABLISHED,
         // FIN-WAIT-1, FIN-WAIT-2, CLOSE-WAIT, CLOSING, LAST-ACK, TIME-WAIT),
@@ -412,14 +610,68 @@ impl Connection {
         // acknowledgment segment containing the current send-sequence number
         // and an acknowledgment indicating the next sequence number expected
         // to be received, and the connection remains in the same state.
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
+        self.tcp.rst = true;
         self.write(nic, self.send.nxt, 0)?;
+        self.tcp.rst = false;
         Ok(())
     }
 
-    pub(crate) fn on_tick(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        // TODO: check if it is triggered in closed state
+    pub(crate) fn on_tick(&mut self, nic: &mut dyn Device) -> io::Result<()> {
+        let now = self.clock.now();
+        if self.timers.state != self.state {
+            self.timers.state = self.state;
+            self.timers.state_since = now;
+        }
+
+        if self.rst_pending {
+            self.rst_pending = false;
+            if !matches!(self.state, State::SynSent | State::TimeWait | State::Closed) {
+                self.send_rst(nic)?;
+            }
+            self.state = State::Closed;
+            self.unacked.clear();
+            if self.error.is_none() {
+                self.error = Some(Error::ConnectionAborted);
+            }
+            return Ok(());
+        }
+
+        if let Some(keepalive) = self.keepalive {
+            // only idle connections are probed, retransmissions watch over unacknowledged data
+            let idle =
+                matches!(self.state, State::Estab | State::CloseWait) && self.unacked.is_empty();
+            // a due time too far out to represent is never reached
+            let due = keepalive
+                .interval
+                .checked_mul(self.probes_sent)
+                .and_then(|d| d.checked_add(keepalive.idle));
+            if idle && due.is_some_and(|due| now.duration_since(self.last_heard) >= due) {
+                if self.probes_sent == keepalive.probes {
+                    self.abort(Error::TimedOut);
+                    return Ok(());
+                }
+                // a segment just below SND.NXT makes a live peer answer with an ACK
+                let seq = self.send.nxt.wrapping_sub(1);
+                self.write(nic, seq, 0)?;
+                self.timers.send_times.remove(&seq);
+                self.probes_sent += 1;
+            }
+        }
+
+        if (self.state == State::TimeWait || (self.orphaned && self.state == State::FinWait2))
+            && now.duration_since(self.timers.state_since) >= TIME_WAIT
+        {
+            self.state = State::Closed;
+        }
+
+        // tell the peer about space reads freed up, once it is worth a segment (RFC 1122 S4.2.3.3)
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            let threshold = std::cmp::max(self.buffers.recv / 2, 1);
+            if self.recv_window() as usize >= self.tcp.window_size as usize + threshold {
+                self.write(nic, self.send.nxt, 0)?;
+            }
+        }
+
         if let State::FinWait2 | State::TimeWait | State::Closed = self.state {
             // we have shutdown our write side and the other side acked, no need to (re)transmit anything
             return Ok(());
@@ -429,35 +681,53 @@ impl Connection {
             .closed_at
             .unwrap_or(self.send.nxt)
             .wrapping_sub(self.send.una);
-        let nunsent_data = self.unacked.len() as u32 - nunacked_data;
+        // the SYN takes up a sequence number but is not in unacked
+        let nunsent_data = (self.unacked.len() as u32).saturating_sub(nunacked_data);
 
         let waited_for = self
             .timers
             .send_times
             .range(self.send.una..)
             .next()
-            .map(|t| t.1.elapsed());
+            .map(|t| now.duration_since(*t.1));
 
-        let should_retransmit = if let Some(waited_for) = waited_for {
-            waited_for > time::Duration::from_secs(1)
-                && waited_for.as_secs_f64() > 1.5 * self.timers.srtt
-        } else {
-            false
-        };
+        let should_retransmit = waited_for.is_some_and(|w| w > self.rto());
+
+        if let State::SynSent = self.state {
+            // send our SYN the first time around, and again if it got lost
+            if should_retransmit || self.send.nxt == self.send.iss {
+                if should_retransmit {
+                    self.retransmits += 1;
+                }
+                self.tcp.syn = true;
+                self.write(nic, self.send.iss, 0)?;
+            }
+            return Ok(());
+        }
 
         if should_retransmit {
+            if self.orphaned && self.retries == ORPHAN_RETRIES {
+                // the peer seems gone and nobody waits for the close, reset rather than keep the quad forever
+                self.reset();
+                return Ok(());
+            }
+
             // we should retransmit!
-            let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
-            if resend < self.send.wnd as u32 && self.closed {
+            // with the peer's window closed, this probes it with a single byte
+            let wnd = std::cmp::max(self.send.wnd, 1) as u32;
+            let resend = std::cmp::min(self.unacked.len() as u32, wnd);
+            if resend < wnd && self.closed {
                 // can we include the FIN?
                 self.tcp.fin = true;
                 self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
             }
 
-            if (resend == 0) {
+            if resend == 0 && !self.tcp.fin {
                 return Ok(());
             };
 
+            self.retries += 1;
+            self.retransmits += 1;
             self.write(nic, self.send.una, resend as usize)?;
         } else {
             // we should send new data if have new data and space in the window
@@ -465,7 +735,7 @@ impl Connection {
                 return Ok(());
             }
 
-            let allowed = self.send.wnd as u32 - nunacked_data;
+            let allowed = (self.send.wnd as u32).saturating_sub(nunacked_data);
             if allowed == 0 {
                 return Ok(());
             }
@@ -476,7 +746,7 @@ impl Connection {
                 self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
             }
 
-            if (send == 0) {
+            if send == 0 && !self.tcp.fin {
                 return Ok(());
             };
 
@@ -489,11 +759,22 @@ impl Connection {
 
     pub(crate) fn on_packet<'a>(
         &mut self,
-        nic: &mut tun_tap::Iface,
+        nic: &mut dyn Device,
         ip_h: etherparse::Ipv4HeaderSlice<'a>,
         tcp_h: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8],
     ) -> io::Result<Available> {
+        // anything from the peer answers our keepalive probes
+        self.last_heard = self.clock.now();
+        self.probes_sent = 0;
+
+        if let State::SynSent = self.state {
+            return self.on_syn_sent(nic, tcp_h);
+        }
+
+        // reads may have opened the window since we last advertised it
+        self.recv.wnd = self.recv_window();
+
         // first check that sequence numbers are valid (RFC 793 S3.3)
         //
         // valid segment check okay if it acks at least one byte, which means that at least one of the following is true
@@ -542,14 +823,35 @@ impl Connection {
 
         if !okay {
             eprintln!("NOT OKAY");
-            self.write(nic, self.send.nxt, 0)?;
+            // an unacceptable RST is dropped without an answer
+            if !tcp_h.rst() {
+                self.write(nic, self.send.nxt, 0)?;
+            }
+            return Ok(self.availability());
+        }
+
+        // second, check the RST bit (RFC 793 S3.9)
+        if tcp_h.rst() {
+            match self.state {
+                State::Closing | State::LastAck | State::TimeWait => self.state = State::Closed,
+                _ => self.abort(Error::ConnectionReset),
+            }
+            self.unacked.clear();
+            return Ok(self.availability());
+        }
+
+        // fourth, a SYN in the window is an error: reset the connection
+        if tcp_h.syn() && self.is_synchronized() && self.state != State::TimeWait {
+            self.send_rst(nic)?;
+            self.abort(Error::ProtocolViolation("SYN in the receive window"));
+            self.unacked.clear();
             return Ok(self.availability());
         }
 
         if !tcp_h.ack() {
             if tcp_h.syn() {
                 // got SYN part of initial handshake
-                assert!(data.is_empty());
+                // TODO: queue data that came with the SYN instead of dropping it
                 self.recv.nxt = seqn.wrapping_add(1);
             }
             return Ok(self.availability());
@@ -570,7 +872,27 @@ impl Connection {
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait
+        | State::Closing
+        | State::LastAck = self.state
+        {
+            // SND.UNA =< SEG.ACK =< SND.NXT updates the peer's window, unless the
+            // segment is older than the one of the last update (RFC 793 S3.9)
+            if is_between_wrapped(
+                self.send.una.wrapping_sub(1),
+                ackn,
+                self.send.nxt.wrapping_add(1),
+            ) && (wrapping_lt(self.send.wl1, seqn)
+                || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2)))
+            {
+                self.send.wnd = tcp_h.window_size();
+                self.send.wl1 = seqn;
+                self.send.wl2 = ackn;
+            }
+
             if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
                 println!(
                     "ack for {} (last: {}); prune in {:?}",
@@ -610,12 +932,15 @@ impl Connection {
 
                     let acked_data_end =
                         std::cmp::min(ackn.wrapping_sub(data_start) as usize, self.unacked.len());
-                    self.unacked.drain(..acked_data_end);
+                    self.unacked.advance(acked_data_end);
 
+                    let now = self.clock.now();
                     self.timers.send_times.retain(|&seq, sent| {
                         if is_between_wrapped(self.send.una, seq, ackn) {
-                            self.timers.srtt =
-                                0.8 * self.timers.srtt + (1.0 - 0.8) * sent.elapsed().as_secs_f64();
+                            let rtt = now.duration_since(*sent).as_secs_f64();
+                            self.timers.rttvar =
+                                0.75 * self.timers.rttvar + 0.25 * (self.timers.srtt - rtt).abs();
+                            self.timers.srtt = 0.8 * self.timers.srtt + (1.0 - 0.8) * rtt;
                             false
                         } else {
                             true
@@ -624,11 +949,9 @@ impl Connection {
 
                     //----------------------------------------------------------------------------------
                 }
+                self.retries = 0;
                 self.send.una = ackn;
             }
-
-            // TODO: if unacked empty and waiting flush, notify
-            // TODO: update window
         }
 
         // receive ack for out FIN
@@ -657,15 +980,25 @@ impl Connection {
 
         if !data.is_empty() {
             if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-                let mut unread_data_at = self.recv.nxt.wrapping_sub(seqn) as usize;
-                if unread_data_at > data.len() {
-                    // we must have received a re-transmitted FIN that we ahve already seen
-                    // nxt points to beyond the fin, but the fin is not in data
-                    assert_eq!(unread_data_at, data.len() + 1);
-                    unread_data_at = 0;
-                }
+                // segments that start beyond RCV.NXT are not reassembled, only acked,
+                // and a re-transmission may overlap data we already have
+                let unread_data_at = if wrapping_lt(self.recv.nxt, seqn) {
+                    data.len()
+                } else {
+                    std::cmp::min(self.recv.nxt.wrapping_sub(seqn) as usize, data.len())
+                };
 
-                self.incoming.extend(&data[unread_data_at..]);
+                // only as much as fits into the receive buffer, the peer retransmits the rest
+                let new_data = &data[unread_data_at..];
+                let accepted = if self.rd_shutdown {
+                    new_data.len()
+                } else {
+                    let room = self.buffers.recv.saturating_sub(self.incoming.len());
+                    let accepted = std::cmp::min(new_data.len(), room);
+                    self.incoming.extend_from_slice(&new_data[..accepted]);
+                    accepted
+                };
+                self.bytes_received += accepted as u64;
 
                 /*
                 Once the TCP takes responsibility for the data it advances
@@ -673,7 +1006,7 @@ impl Connection {
                 apporopriate to the current buffer availability. The total of
                 RCV.NXT and RCV.WND should not be reduced.
                 */
-                self.recv.nxt = seqn.wrapping_add(data.len() as u32);
+                self.recv.nxt = self.recv.nxt.wrapping_add(accepted as u32);
 
                 /* Send an acknowledgment of the form: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK> */
                 // TODO: maybe just tick to piggyback ack on data??
@@ -681,7 +1014,9 @@ impl Connection {
             }
         }
 
-        if tcp_h.fin() {
+        // a FIN only counts once everything in front of it has been received
+        let fin_at = seqn.wrapping_add(data.len() as u32);
+        if tcp_h.fin() && fin_at == self.recv.nxt {
             match self.state {
                 State::FinWait2 => {
                     // we are done with connection
@@ -697,18 +1032,140 @@ impl Connection {
                 State::Estab => {
                     self.recv.nxt = self.recv.nxt.wrapping_add(1);
                     self.write(nic, self.send.nxt, 0)?;
-                    self.state = State::TimeWait;
+                    self.state = State::CloseWait;
                 }
                 // we are not expecting FIN flag in other states
-                _ => unimplemented!(),
+                _ => {}
+            }
+        } else if tcp_h.fin() && fin_at.wrapping_add(1) == self.recv.nxt {
+            // re-transmitted FIN that we have already seen, our ACK must have been lost
+            if let State::CloseWait | State::Closing | State::LastAck | State::TimeWait = self.state
+            {
+                self.write(nic, self.send.nxt, 0)?;
             }
         }
 
         Ok(self.availability())
     }
 
+    /// Segment arriving while our SYN is outstanding (RFC 793 S3.9, SYN-SENT).
+    fn on_syn_sent(
+        &mut self,
+        nic: &mut dyn Device,
+        tcp_h: etherparse::TcpHeaderSlice,
+    ) -> io::Result<Available> {
+        let ackn = tcp_h.acknowledgment_number();
+        if tcp_h.ack() && !is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1)) {
+            // acks something we never sent
+            // TODO: RST : <SEQ=SEG.ACK><CTL=RST>
+            return Ok(self.availability());
+        }
+
+        if tcp_h.rst() {
+            if tcp_h.ack() {
+                self.abort(Error::ConnectionRefused);
+            }
+            return Ok(self.availability());
+        }
+
+        if !tcp_h.syn() {
+            return Ok(self.availability());
+        }
+
+        self.recv.irs = tcp_h.sequence_number();
+        self.recv.nxt = tcp_h.sequence_number().wrapping_add(1);
+        self.send.wnd = tcp_h.window_size();
+        self.send.wl1 = tcp_h.sequence_number();
+        self.send.wl2 = ackn;
+        self.tcp.ack = true;
+
+        if tcp_h.ack() {
+            // our SYN has been ACKed
+            self.send.una = ackn;
+            let una = self.send.una;
+            self.timers
+                .send_times
+                .retain(|&seq, _| !wrapping_lt(seq, una));
+            self.state = State::Estab;
+            self.write(nic, self.send.nxt, 0)?;
+        } else {
+            // simultaneous open, send SYN,ACK
+            self.state = State::SynRcvd;
+            self.tcp.syn = true;
+            self.write(nic, self.send.iss, 0)?;
+        }
+        Ok(self.availability())
+    }
+
+    /// Our FIN has been acknowledged, or the connection is gone.
+    pub(crate) fn is_fin_acked(&self) -> bool {
+        matches!(
+            self.state,
+            State::FinWait2 | State::TimeWait | State::Closed
+        )
+    }
+
+    /// The stream is gone and so is the connection, the manager can forget about it.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.orphaned && self.state == State::Closed
+    }
+
+    /// Abortive close: queued data is discarded and the next tick sends a RST.
+    pub(crate) fn reset(&mut self) {
+        self.rst_pending = true;
+    }
+
+    /// Retransmission timeout: 1.5 times the smoothed RTT, at least a second.
+    fn rto(&self) -> time::Duration {
+        time::Duration::from_secs_f64(f64::max(1.0, 1.5 * self.timers.srtt))
+    }
+
+    pub(crate) fn info(&self) -> TcpInfo {
+        TcpInfo {
+            state: self.state,
+            snd_una: self.send.una,
+            snd_nxt: self.send.nxt,
+            snd_wnd: self.send.wnd,
+            rcv_nxt: self.recv.nxt,
+            rcv_wnd: self.recv_window(),
+            srtt: time::Duration::from_secs_f64(self.timers.srtt),
+            rttvar: time::Duration::from_secs_f64(self.timers.rttvar),
+            rto: self.rto(),
+            cwnd: None,
+            bytes_in_flight: self.send.nxt.wrapping_sub(self.send.una),
+            retransmits: self.retransmits,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            send_buffered: self.unacked.len(),
+            recv_buffered: self.incoming.len(),
+        }
+    }
+
+    /// The handshake is done (RFC 793 "synchronized" states).
+    fn is_synchronized(&self) -> bool {
+        !matches!(self.state, State::SynSent | State::SynRcvd | State::Closed)
+    }
+
+    pub(crate) fn is_connecting(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynRcvd)
+    }
+
+    /// Gives up on the connection after `err`, the stream gets `err` on its next call.
+    pub(crate) fn abort(&mut self, err: Error) {
+        self.state = State::Closed;
+        self.error = Some(err);
+    }
+
+    pub(crate) fn check_error(&self) -> io::Result<()> {
+        match &self.error {
+            Some(e) => Err(e.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Shuts down the write side: our FIN goes out after the pending data.
     pub(crate) fn close(&mut self) -> io::Result<()> {
-        self.closed = true;
+        self.check_close()?;
         match self.state {
             State::SynRcvd | State::Estab => {
                 self.state = State::FinWait1;
@@ -716,18 +1173,81 @@ impl Connection {
             State::CloseWait => {
                 self.state = State::LastAck;
             }
-            State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck => {}
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotConnected,
-                    "already closing",
-                ))
-            }
+            _ => {}
+        }
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Fails like `close` would, without closing anything.
+    pub(crate) fn check_close(&self) -> io::Result<()> {
+        match self.state {
+            State::SynRcvd
+            | State::Estab
+            | State::CloseWait
+            | State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::LastAck => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "already closing",
+            )),
+        }
+    }
+
+    /// Shuts down the read side: buffered and future data is discarded, reads return 0.
+    pub(crate) fn shutdown_read(&mut self) -> io::Result<()> {
+        if let State::SynSent | State::TimeWait | State::Closed = self.state {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "not connected"));
         }
+        self.rd_shutdown = true;
+        self.incoming.clear();
         Ok(())
     }
 }
 
+/// Answers a segment that belongs to no connection (RFC 793 S3.4 Reset Generation).
+///
+/// The RST takes its sequence number from the segment's ACK, or acknowledges
+/// the segment if it has none. A RST is never answered.
+pub(crate) fn send_reset(
+    nic: &mut dyn Device,
+    ip_h: &etherparse::Ipv4HeaderSlice,
+    tcp_h: &etherparse::TcpHeaderSlice,
+    data: &[u8],
+) -> io::Result<()> {
+    if tcp_h.rst() {
+        return Ok(());
+    }
+
+    let seq = if tcp_h.ack() {
+        tcp_h.acknowledgment_number()
+    } else {
+        0
+    };
+    let b = etherparse::PacketBuilder::ipv4(
+        ip_h.destination_addr().octets(),
+        ip_h.source_addr().octets(),
+        64,
+    )
+    .tcp(tcp_h.destination_port(), tcp_h.source_port(), seq, 0)
+    .rst();
+    let b = if tcp_h.ack() {
+        b
+    } else {
+        // SYN and FIN take up a sequence number each
+        let seg_len = data.len() as u32 + tcp_h.syn() as u32 + tcp_h.fin() as u32;
+        b.ack(tcp_h.sequence_number().wrapping_add(seg_len))
+    };
+
+    let mut buf = Vec::with_capacity(b.size(0));
+    b.write(&mut buf, &[])
+        .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+    nic.send(&buf)?;
+    Ok(())
+}
+
 fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     // From RFC1323
     // TCP determines if a data segment is 'old' or new by testing
@@ -0,0 +1,133 @@
+//! The async sockets, driven by a minimal executor that parks the thread.
+#![cfg(feature = "async")]
+
+use std::future::{poll_fn, Future};
+use std::io::{self, Write};
+use std::net::SocketAddrV4;
+use std::pin::{pin, Pin};
+use std::sync::{mpsc, Arc};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use futures_io::{AsyncRead, AsyncWrite};
+use tcpRust::async_io::{TcpListener, TcpStream};
+
+mod common;
+use common::{connected, pair, settle, SERVER};
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut f = pin!(f);
+    loop {
+        match f.as_mut().poll(&mut cx) {
+            Poll::Ready(out) => return out,
+            // spurious unparks only cost another poll
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[test]
+fn accept_read_write() {
+    let (mut server, mut client) = pair();
+
+    let mut listener = TcpListener::from(server.bind(8080).unwrap());
+    let c = client.connect(SocketAddrV4::new(SERVER, 8080)).unwrap();
+    let mut c = TcpStream::from(c);
+
+    block_on(async {
+        let mut s = listener.accept().await.unwrap();
+
+        let n = poll_fn(|cx| Pin::new(&mut c).poll_write(cx, b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(n, 5);
+        poll_fn(|cx| Pin::new(&mut c).poll_flush(cx)).await.unwrap();
+
+        let mut buf = [0u8; 8];
+        let n = poll_fn(|cx| Pin::new(&mut s).poll_read(cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        // closing waits for the FIN to be acknowledged, then the peer reads the end
+        poll_fn(|cx| Pin::new(&mut c).poll_close(cx)).await.unwrap();
+        let n = poll_fn(|cx| Pin::new(&mut s).poll_read(cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(n, 0);
+    });
+}
+
+#[test]
+fn accept_waits_behind_blocking_accepts() {
+    let (mut server, mut client) = pair();
+    let listener = server.bind(8080).unwrap();
+
+    let blocking = listener.try_clone().unwrap();
+    let waiting = thread::spawn(move || blocking.accept().unwrap().peer_addr().unwrap());
+    settle();
+
+    let connector = thread::spawn(move || {
+        let first = client.connect(SocketAddrV4::new(SERVER, 8080)).unwrap();
+        settle();
+        let second = client.connect(SocketAddrV4::new(SERVER, 8080)).unwrap();
+        (first, second, client)
+    });
+
+    let mut listener = TcpListener::from(listener);
+    let s = block_on(listener.accept()).unwrap();
+    let (first, second, _client) = connector.join().unwrap();
+    assert_eq!(waiting.join().unwrap(), first.local_addr().unwrap());
+    assert_eq!(
+        s.get_ref().peer_addr().unwrap(),
+        second.local_addr().unwrap()
+    );
+}
+
+#[test]
+fn nonblocking_accept() {
+    let (mut server, _client) = pair();
+    let listener = server.bind(8080).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let mut listener = TcpListener::from(listener);
+
+    let e = block_on(listener.accept()).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn tasks_reading_clones_are_all_woken() {
+    let (mut server, mut client) = pair();
+    let (s, mut c) = connected(&mut server, &mut client, 8080);
+
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..2 {
+        let mut s = TcpStream::from(s.try_clone().unwrap());
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 8];
+            let n = block_on(poll_fn(|cx| Pin::new(&mut s).poll_read(cx, &mut buf))).unwrap();
+            tx.send(buf[..n].to_vec()).unwrap();
+        });
+    }
+    settle();
+
+    // whichever task reads the first byte, the other one still gets the second
+    let mut received = Vec::new();
+    for b in [b"a", b"b"] {
+        c.write_all(b).unwrap();
+        received.extend(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+    assert_eq!(received, b"ab");
+}
@@ -0,0 +1,45 @@
+//! Helpers shared by the tests that run interfaces over `tcpRust::sim::link`.
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tcpRust::sim::{link, LinkDevice};
+use tcpRust::{Clock, Device, Interface, SystemClock, TcpStream};
+
+pub const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+pub const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+
+/// A server and a client interface linked to each other; the server's timers go by `clock`.
+pub fn pair_with_clock(clock: Arc<dyn Clock>) -> (Interface<LinkDevice>, Interface<LinkDevice>) {
+    let (a, b) = link();
+    let mut server = Interface::with_device(a, clock);
+    let mut client = Interface::with_device(b, Arc::new(SystemClock));
+    client.set_local_addr(CLIENT);
+    server.set_shutdown_timeout(Duration::from_secs(1));
+    client.set_shutdown_timeout(Duration::from_secs(1));
+    (server, client)
+}
+
+pub fn pair() -> (Interface<LinkDevice>, Interface<LinkDevice>) {
+    pair_with_clock(Arc::new(SystemClock))
+}
+
+/// An established connection to `port`, the server's end first.
+pub fn connected<D: Device + Send + 'static>(
+    server: &mut Interface<D>,
+    client: &mut Interface<LinkDevice>,
+    port: u16,
+) -> (TcpStream, TcpStream) {
+    let listener = server.bind(port).unwrap();
+    let c = client.connect(SocketAddrV4::new(SERVER, port)).unwrap();
+    let s = listener.accept().unwrap();
+    (s, c)
+}
+
+/// Lets the packet loops run for a few ticks.
+pub fn settle() {
+    thread::sleep(Duration::from_millis(100));
+}
@@ -0,0 +1,39 @@
+//! RFC 793 state machine conformance, see `tcpRust::sim::script` for the format.
+
+macro_rules! script {
+    ($name:ident) => {
+        #[test]
+        fn $name() {
+            tcpRust::sim::script::run_file(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/scripts/",
+                stringify!($name),
+                ".pkt"
+            ))
+            .unwrap();
+        }
+    };
+}
+
+script!(passive_open);
+script!(active_open);
+script!(connect_refused);
+script!(receive_data);
+script!(send_data);
+script!(retransmit);
+script!(out_of_window);
+script!(active_close);
+script!(simultaneous_close);
+script!(passive_close);
+script!(bad_checksum);
+script!(drop_close);
+script!(drop_reset);
+script!(listener_drop);
+script!(shutdown);
+script!(shutdown_refuse);
+script!(shutdown_read);
+script!(receive_window);
+script!(peer_reset);
+script!(syn_in_window);
+script!(keepalive);
+script!(orphan_retries);
@@ -0,0 +1,427 @@
+//! The socket API end to end: two interfaces with real packet loops, wired
+//! together by `tcpRust::sim::link`.
+
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::net::{Shutdown, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use tcpRust::sim::{link, LinkDevice};
+use tcpRust::{
+    Bytes, Device, Error, Interface, Keepalive, State, SystemClock, TcpStream, VirtualClock,
+};
+
+mod common;
+use common::{connected, pair, pair_with_clock, settle, CLIENT, SERVER};
+
+/// Passes packets on until `down` is set, then loses whatever comes in.
+struct Unplugged {
+    link: LinkDevice,
+    down: Arc<AtomicBool>,
+}
+
+impl Device for Unplugged {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.link.send(buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let n = self.link.recv(buf, timeout)?;
+        if self.down.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        Ok(n)
+    }
+}
+
+#[test]
+fn exchange_data() {
+    let (mut server, mut client) = pair();
+    let (mut s, mut c) = connected(&mut server, &mut client, 8080);
+
+    c.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    s.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn addresses() {
+    let (mut server, mut client) = pair();
+    let (s, c) = connected(&mut server, &mut client, 8080);
+
+    assert_eq!(c.peer_addr().unwrap(), SocketAddrV4::new(SERVER, 8080));
+    assert_eq!(s.local_addr().unwrap(), SocketAddrV4::new(SERVER, 8080));
+    assert_eq!(*c.local_addr().unwrap().ip(), CLIENT);
+    assert_eq!(s.peer_addr().unwrap(), c.local_addr().unwrap());
+}
+
+#[test]
+fn write_waits_for_room_and_flush_for_acks() {
+    let (mut server, mut client) = pair();
+    let (mut s, mut c) = connected(&mut server, &mut client, 8080);
+    c.set_send_buffer_size(16).unwrap();
+
+    let data: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+    let reader = thread::spawn(move || {
+        let mut received = Vec::new();
+        s.read_to_end(&mut received).unwrap();
+        received
+    });
+
+    c.write_all(&data).unwrap();
+    c.flush().unwrap();
+    assert_eq!(c.info().unwrap().send_buffered, 0);
+    c.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(reader.join().unwrap(), data);
+}
+
+#[test]
+fn nonblocking() {
+    let (mut server, mut client) = pair();
+    let listener = server.bind(8080).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let e = listener.accept().err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+    let mut c = client.connect(SocketAddrV4::new(SERVER, 8080)).unwrap();
+    let mut s = loop {
+        match listener.accept() {
+            Ok(s) => break s,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => settle(),
+            Err(e) => panic!("{}", e),
+        }
+    };
+
+    s.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(
+        s.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    c.write_all(b"hello").unwrap();
+    let n = loop {
+        match s.read(&mut buf) {
+            Ok(n) => break n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => settle(),
+            Err(e) => panic!("{}", e),
+        }
+    };
+    assert_eq!(&buf[..n], b"hello");
+}
+
+#[test]
+fn read_timeout() {
+    let (mut server, mut client) = pair();
+    let (mut s, mut c) = connected(&mut server, &mut client, 8080);
+
+    s.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let e = s.read(&mut [0u8; 8]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    let e = e.get_ref().and_then(|e| e.downcast_ref::<Error>());
+    assert_eq!(e, Some(&Error::TimedOut));
+
+    // too far out to have a deadline, like no timeout at all
+    s.set_read_timeout(Some(Duration::MAX)).unwrap();
+    c.write_all(b"hi").unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(s.read(&mut buf).unwrap(), 2);
+}
+
+#[test]
+fn blocked_reader_is_woken_by_its_connection() {
+    let (mut server, mut client) = pair();
+    let listener = server.bind(8080).unwrap();
+    let mut c1 = client.connect(SocketAddrV4::new(SERVER, 8080)).unwrap();
+    let mut s1 = listener.accept().unwrap();
+    let mut c2 = client.connect(SocketAddrV4::new(SERVER, 8080)).unwrap();
+    let mut s2 = listener.accept().unwrap();
+
+    let reader = thread::spawn(move || {
+        let mut buf = [0u8; 8];
+        let n = s1.read(&mut buf).unwrap();
+        buf[..n].to_vec()
+    });
+
+    c2.write_all(b"other").unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(s2.read(&mut buf).unwrap(), 5);
+    settle();
+    assert!(!reader.is_finished());
+
+    c1.write_all(b"mine").unwrap();
+    assert_eq!(reader.join().unwrap(), b"mine");
+}
+
+#[test]
+fn incoming_in_connect_order() {
+    let (mut server, mut client) = pair();
+    let listener = server.bind(8080).unwrap();
+    let clients: Vec<TcpStream> = (0..3)
+        .map(|_| client.connect(SocketAddrV4::new(SERVER, 8080)).unwrap())
+        .collect();
+
+    let accepted: Vec<SocketAddrV4> = listener
+        .incoming()
+        .take(3)
+        .map(|s| s.unwrap().peer_addr().unwrap())
+        .collect();
+    let connected: Vec<SocketAddrV4> = clients.iter().map(|c| c.local_addr().unwrap()).collect();
+    assert_eq!(accepted, connected);
+}
+
+#[test]
+fn clones_accept_in_the_order_they_started_waiting() {
+    let (mut server, mut client) = pair();
+    let listener = server.bind(8080).unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    for (i, l) in [listener.try_clone().unwrap(), listener.try_clone().unwrap()]
+        .into_iter()
+        .enumerate()
+    {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let s = l.accept().unwrap();
+            tx.send((i, s.peer_addr().unwrap())).unwrap();
+        });
+        settle();
+    }
+
+    for i in 0..2 {
+        let c = client.connect(SocketAddrV4::new(SERVER, 8080)).unwrap();
+        assert_eq!(rx.recv().unwrap(), (i, c.local_addr().unwrap()));
+    }
+}
+
+#[test]
+fn split() {
+    let (mut server, mut client) = pair();
+    let (s, mut c) = connected(&mut server, &mut client, 8080);
+
+    let (mut r, mut w) = s.into_split();
+    let reader = thread::spawn(move || {
+        let mut received = Vec::new();
+        r.read_to_end(&mut received).unwrap();
+        received
+    });
+    w.write_all(b"ping").unwrap();
+    // the write half shuts down writing when dropped
+    drop(w);
+
+    let mut received = Vec::new();
+    c.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"ping");
+
+    c.write_all(b"pong").unwrap();
+    c.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(reader.join().unwrap(), b"pong");
+}
+
+#[test]
+fn split_borrowed() {
+    let (mut server, mut client) = pair();
+    let (s, mut c) = connected(&mut server, &mut client, 8080);
+
+    let (mut r, mut w) = s.split();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut buf = [0u8; 4];
+            r.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping");
+        });
+        c.write_all(b"ping").unwrap();
+    });
+    w.write_all(b"pong").unwrap();
+
+    let mut buf = [0u8; 4];
+    c.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[test]
+fn peek_and_vectored_io() {
+    let (mut server, mut client) = pair();
+    let (mut s, mut c) = connected(&mut server, &mut client, 8080);
+
+    let n = c
+        .write_vectored(&[IoSlice::new(b"hel"), IoSlice::new(b"lo")])
+        .unwrap();
+    assert_eq!(n, 5);
+    settle();
+
+    let mut buf = [0u8; 8];
+    assert_eq!(s.peek(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+
+    let (mut a, mut b) = ([0u8; 2], [0u8; 3]);
+    let n = s
+        .read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)])
+        .unwrap();
+    assert_eq!(n, 5);
+    assert_eq!((&a, &b), (b"he", b"llo"));
+}
+
+#[test]
+fn send_and_recv_bytes() {
+    let (mut server, mut client) = pair();
+    let (s, c) = connected(&mut server, &mut client, 8080);
+
+    let mut data = Bytes::from_static(b"chunk");
+    c.send_bytes(&mut data).unwrap();
+    assert!(data.is_empty());
+    assert_eq!(s.recv_bytes().unwrap(), Bytes::from_static(b"chunk"));
+
+    c.shutdown(Shutdown::Write).unwrap();
+    assert!(s.recv_bytes().unwrap().is_empty());
+}
+
+#[test]
+fn info() {
+    let (mut server, mut client) = pair();
+    let (mut s, mut c) = connected(&mut server, &mut client, 8080);
+
+    c.write_all(b"hello").unwrap();
+    c.flush().unwrap();
+    let mut buf = [0u8; 5];
+    s.read_exact(&mut buf).unwrap();
+
+    let info = c.info().unwrap();
+    assert_eq!(info.state, State::Estab);
+    assert_eq!(info.bytes_sent, 5);
+    assert_eq!(info.bytes_in_flight, 0);
+    assert_eq!(info.retransmits, 0);
+    let info = s.info().unwrap();
+    assert_eq!(info.bytes_received, 5);
+    assert_eq!(info.recv_buffered, 0);
+}
+
+#[test]
+fn idle_connection_counts_no_retransmits() {
+    let clock = VirtualClock::new();
+    let (mut server, mut client) = pair_with_clock(Arc::new(clock.clone()));
+    // the grace period goes by the virtual clock, which stands still by then
+    server.set_shutdown_timeout(Duration::ZERO);
+    let (mut s, mut c) = connected(&mut server, &mut client, 8080);
+
+    c.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    s.read_exact(&mut buf).unwrap();
+
+    // well past the retransmission timeout of the ACK the server sent last
+    clock.advance(Duration::from_secs(100));
+    settle();
+    assert_eq!(s.info().unwrap().retransmits, 0);
+}
+
+#[test]
+fn keepalive_with_huge_interval() {
+    let clock = VirtualClock::new();
+    let (a, b) = link();
+    let down = Arc::new(AtomicBool::new(false));
+    let unplugged = Unplugged {
+        link: a,
+        down: down.clone(),
+    };
+    let mut server = Interface::with_device(unplugged, Arc::new(clock.clone()));
+    let mut client = Interface::with_device(b, Arc::new(SystemClock));
+    client.set_local_addr(CLIENT);
+    server.set_shutdown_timeout(Duration::ZERO);
+    client.set_shutdown_timeout(Duration::ZERO);
+    let (s, _c) = connected(&mut server, &mut client, 8080);
+
+    // the probes go unanswered, once the handshake is done
+    settle();
+    down.store(true, Ordering::SeqCst);
+    s.set_keepalive(Some(Keepalive {
+        idle: Duration::from_secs(1),
+        interval: Duration::MAX,
+        probes: 2,
+    }))
+    .unwrap();
+    for _ in 0..3 {
+        clock.advance(Duration::from_secs(2));
+        settle();
+    }
+    assert_eq!(s.info().unwrap().state, State::Estab);
+}
+
+#[test]
+fn shutdown_deadline_goes_by_the_interface_clock() {
+    let clock = VirtualClock::new();
+    let (a, b) = link();
+    let down = Arc::new(AtomicBool::new(false));
+    let unplugged = Unplugged {
+        link: a,
+        down: down.clone(),
+    };
+    let mut server = Interface::with_device(unplugged, Arc::new(clock.clone()));
+    let mut client = Interface::with_device(b, Arc::new(SystemClock));
+    client.set_local_addr(CLIENT);
+    client.set_shutdown_timeout(Duration::ZERO);
+    let (_s, _c) = connected(&mut server, &mut client, 8080);
+
+    // our FIN is never acknowledged, only the grace period ends the shutdown
+    settle();
+    down.store(true, Ordering::SeqCst);
+    let stopping = thread::spawn(move || server.shutdown(Duration::from_millis(200)));
+    settle();
+    assert!(!stopping.is_finished());
+    clock.advance(Duration::from_millis(200));
+    stopping.join().unwrap().unwrap();
+}
+
+#[test]
+fn shutdown_wakes_blocked_writers() {
+    let (mut server, mut client) = pair();
+    let (s, c) = connected(&mut server, &mut client, 8080);
+    s.set_recv_buffer_size(16).unwrap();
+    c.set_send_buffer_size(16).unwrap();
+
+    // the peer never reads, so the writer blocks once both buffers are full
+    let mut w = c.try_clone().unwrap();
+    let writer = thread::spawn(move || w.write_all(&[0u8; 4096]));
+    settle();
+    assert!(!writer.is_finished());
+
+    c.shutdown(Shutdown::Write).unwrap();
+    settle();
+    assert!(writer.is_finished());
+    let e = writer.join().unwrap().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WriteZero);
+    drop(s);
+}
+
+#[test]
+fn shutdown_after_close_is_not_connected() {
+    let (mut server, mut client) = pair();
+    let (s, c) = connected(&mut server, &mut client, 8080);
+
+    c.shutdown(Shutdown::Both).unwrap();
+    settle();
+    s.shutdown(Shutdown::Both).unwrap();
+    settle();
+
+    for how in [Shutdown::Read, Shutdown::Write, Shutdown::Both] {
+        let e = s.shutdown(how).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+    }
+}
+
+#[test]
+fn send_bytes_keeps_what_did_not_fit() {
+    let (mut server, mut client) = pair();
+    let (s, c) = connected(&mut server, &mut client, 8080);
+    s.set_recv_buffer_size(16).unwrap();
+    c.set_send_buffer_size(16).unwrap();
+    settle();
+    c.set_nonblocking(true).unwrap();
+
+    let mut data = Bytes::from(vec![7u8; 4096]);
+    let e = c.send_bytes(&mut data).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    assert!(!data.is_empty() && data.len() < 4096);
+    assert_eq!(c.info().unwrap().send_buffered, 4096 - data.len());
+}
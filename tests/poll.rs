@@ -0,0 +1,170 @@
+//! `Poll` over interfaces linked by `tcpRust::sim::link`.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddrV4};
+use std::time::Duration;
+
+use tcpRust::{Available, Events, Poll, Token, Trigger};
+
+mod common;
+use common::{connected, pair, settle, SERVER};
+
+const TIMEOUT: Option<Duration> = Some(Duration::from_millis(100));
+
+fn tokens(events: &Events) -> Vec<Token> {
+    events.iter().map(|e| e.token()).collect()
+}
+
+#[test]
+fn level_triggered() {
+    let (mut server, mut client) = pair();
+    let (mut s, mut c) = connected(&mut server, &mut client, 8080);
+    let mut poll = Poll::new(&server);
+    let mut events = Events::with_capacity(8);
+    poll.register(&s, Token(1), Available::READ, Trigger::Level)
+        .unwrap();
+
+    poll.poll(&mut events, TIMEOUT).unwrap();
+    assert!(events.is_empty());
+
+    c.write_all(b"hello").unwrap();
+    poll.poll(&mut events, None).unwrap();
+    assert_eq!(tokens(&events), [Token(1)]);
+    assert!(events.iter().next().unwrap().is_readable());
+
+    // reported as long as the data is there
+    poll.poll(&mut events, TIMEOUT).unwrap();
+    assert_eq!(tokens(&events), [Token(1)]);
+
+    s.read_exact(&mut [0u8; 5]).unwrap();
+    poll.poll(&mut events, TIMEOUT).unwrap();
+    assert!(events.is_empty());
+}
+
+#[test]
+fn edge_triggered() {
+    let (mut server, mut client) = pair();
+    let (mut s, mut c) = connected(&mut server, &mut client, 8080);
+    let mut poll = Poll::new(&server);
+    let mut events = Events::with_capacity(8);
+    poll.register(&s, Token(1), Available::READ, Trigger::Edge)
+        .unwrap();
+
+    c.write_all(b"hello").unwrap();
+    poll.poll(&mut events, None).unwrap();
+    assert_eq!(tokens(&events), [Token(1)]);
+
+    // not again while it stays readable, even though nothing was read
+    poll.poll(&mut events, TIMEOUT).unwrap();
+    assert!(events.is_empty());
+
+    // until it becomes readable again
+    s.read_exact(&mut [0u8; 5]).unwrap();
+    c.write_all(b"again").unwrap();
+    poll.poll(&mut events, None).unwrap();
+    assert_eq!(tokens(&events), [Token(1)]);
+}
+
+#[test]
+fn reregister_and_deregister() {
+    let (mut server, mut client) = pair();
+    let (s, mut c) = connected(&mut server, &mut client, 8080);
+    let mut poll = Poll::new(&server);
+    let mut events = Events::with_capacity(8);
+
+    let e = poll.reregister(&s, Token(1), Available::READ, Trigger::Level);
+    assert_eq!(e.unwrap_err().kind(), io::ErrorKind::NotFound);
+    poll.register(&s, Token(1), Available::READ, Trigger::Level)
+        .unwrap();
+    let e = poll.register(&s, Token(1), Available::READ, Trigger::Level);
+    assert_eq!(e.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+    poll.reregister(&s, Token(2), Available::READ, Trigger::Level)
+        .unwrap();
+    c.write_all(b"hello").unwrap();
+    poll.poll(&mut events, None).unwrap();
+    assert_eq!(tokens(&events), [Token(2)]);
+
+    poll.deregister(&s).unwrap();
+    poll.poll(&mut events, TIMEOUT).unwrap();
+    assert!(events.is_empty());
+    assert_eq!(
+        poll.deregister(&s).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn full_events_do_not_starve_sockets() {
+    let (mut server, mut client) = pair();
+    let mut poll = Poll::new(&server);
+    let mut listeners = Vec::new();
+    let mut clients = Vec::new();
+    for port in 8080..8083 {
+        let listener = server.bind(port).unwrap();
+        poll.register(
+            &listener,
+            Token(port as usize),
+            Available::READ,
+            Trigger::Level,
+        )
+        .unwrap();
+        listeners.push(listener);
+        clients.push(client.connect(SocketAddrV4::new(SERVER, port)).unwrap());
+    }
+
+    // every listener has a connection waiting, one event at a time reports each in turn
+    let mut events = Events::with_capacity(1);
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+        poll.poll(&mut events, None).unwrap();
+        assert_eq!(events.len(), 1);
+        seen.extend(tokens(&events));
+    }
+    seen.sort();
+    assert_eq!(seen, [Token(8080), Token(8081), Token(8082)]);
+}
+
+#[test]
+fn dropped_stream_is_deregistered() {
+    let (mut server, mut client) = pair();
+    let (s, mut c) = connected(&mut server, &mut client, 8080);
+    let mut poll = Poll::new(&server);
+    let mut events = Events::with_capacity(8);
+    poll.register(&s, Token(1), Available::READ, Trigger::Level)
+        .unwrap();
+
+    // the peer closes first, so the connection is gone once ours is acknowledged
+    c.shutdown(Shutdown::Write).unwrap();
+    settle();
+    drop(s);
+    c.read_to_end(&mut Vec::new()).unwrap();
+    settle();
+
+    poll.poll(&mut events, TIMEOUT).unwrap();
+    assert!(events.is_empty());
+}
+
+#[test]
+fn shutdown_makes_sockets_ready() {
+    let (mut server, mut client) = pair();
+    let (s, _c) = connected(&mut server, &mut client, 8080);
+    let listener = server.bind(8081).unwrap();
+    let mut poll = Poll::new(&server);
+    poll.register(&s, Token(1), Available::READ, Trigger::Edge)
+        .unwrap();
+    poll.register(&listener, Token(2), Available::READ, Trigger::Edge)
+        .unwrap();
+
+    server.shutdown(Duration::from_secs(1)).unwrap();
+
+    let mut events = Events::with_capacity(8);
+    let mut seen = Vec::new();
+    while seen.len() < 2 {
+        poll.poll(&mut events, None).unwrap();
+        seen.extend(tokens(&events));
+    }
+    seen.sort();
+    assert_eq!(seen, [Token(1), Token(2)]);
+    assert!(listener.accept().is_err());
+}